@@ -0,0 +1,815 @@
+#![forbid(unsafe_code)]
+
+/// A macro assembler for brainfuck, where cells have names and common idioms are macros
+///
+/// ```text
+/// ; comments run to the end of the line
+/// include "lib.bfa"       ; pulls in another file once, relative to this one
+/// cell a b tmp            ; names the next free cells
+/// cell flag = 10          ; names a specific cell
+///
+/// macro double(x, t) {    ; macros take cells or numbers
+///     move(x, t)
+///     t: [- x: ++ t:]
+/// }
+///
+/// a: +++++                ; `name:` moves to a cell, anything else is plain brainfuck
+/// double(a, tmp)
+/// copy(a, b, tmp) clear(a) move(b, a) set(b, 'A') add(b, 1) sub(b, 2)
+/// a: [> +] at flag        ; after a loop that doesn't end where it started, `at` says where we are
+/// ```
+/// The assembler keeps track of where the data pointer is, so moving to a cell is just the right
+/// number of `<` or `>`. Every macro expands in place, so the result is plain brainfuck instructions
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::brainfuck::Instruction;
+
+/// How deep macros can expand inside of each other, which only recursive macros should get to
+const MAX_DEPTH: usize = 64;
+
+/// Where in the source something is
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+#[derive(Snafu, Debug)]
+pub enum AssembleError {
+    #[snafu(display("Failed to read {}", path.display()))]
+    Read { path: PathBuf, source: io::Error },
+    #[snafu(display("{}: {}", location, message))]
+    Syntax { location: Location, message: String },
+    #[snafu(display("{}: {} is already defined", location, name))]
+    Redefined { location: Location, name: String },
+    #[snafu(display("{}: unknown cell {}", location, name))]
+    UnknownCell { location: Location, name: String },
+    #[snafu(display("{}: unknown macro {}", location, name))]
+    UnknownMacro { location: Location, name: String },
+    #[snafu(display("{}: {} takes {}", location, name, expected))]
+    WrongArguments {
+        location: Location,
+        name: String,
+        expected: String,
+    },
+    #[snafu(display(
+        "{}: don't know where the data pointer is, use `at` after loops that move it",
+        location
+    ))]
+    UnknownPosition { location: Location },
+    #[snafu(display("{}: moves to the left of the first cell", location))]
+    OutOfBounds { location: Location },
+    #[snafu(display("{}: unmatched bracket", location))]
+    UnmatchedBracket { location: Location },
+    #[snafu(display("{}: macros nested too deep expanding {}", location, name))]
+    TooDeep { location: Location, name: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Number(usize),
+    Text(String),
+    Symbol(char),
+}
+
+#[derive(Debug, Clone)]
+enum Arg {
+    Name(String),
+    Number(u8),
+}
+
+#[derive(Debug, Clone)]
+enum StatementKind {
+    /// Moves the data pointer to a cell
+    Select(String),
+    /// Tells the assembler the data pointer is at a cell, without moving it
+    At(String),
+    Call(String, Vec<Arg>),
+    Raw(Instruction),
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    kind: StatementKind,
+    location: Location,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Statement>,
+}
+
+const BUILTINS: [&str; 6] = ["clear", "move", "copy", "set", "add", "sub"];
+
+fn syntax<T>(location: Location, message: impl Into<String>) -> Result<T, AssembleError> {
+    Syntax {
+        location,
+        message: message.into(),
+    }
+    .fail()
+}
+
+fn tokenize(source: &str, file: &Path) -> Result<Vec<(Token, Location)>, AssembleError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        let location = Location {
+            file: file.to_path_buf(),
+            line,
+        };
+
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            ';' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            c if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                    chars.next();
+                }
+
+                match digits.parse() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => return syntax(location, "number is too big"),
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(&c) = chars.peek().filter(|&&c| c.is_alphanumeric() || c == '_') {
+                    name.push(c);
+                    chars.next();
+                }
+
+                Token::Name(name)
+            }
+            '\'' => {
+                let c = chars.next();
+                match (c, chars.next()) {
+                    (Some(c), Some('\'')) if c.is_ascii() => Token::Number(c as usize),
+                    _ => return syntax(location, "character literals must be one ASCII character"),
+                }
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => return syntax(location, "unterminated string"),
+                        Some(c) => text.push(c),
+                    }
+                }
+
+                Token::Text(text)
+            }
+            '(' | ')' | '{' | '}' | ':' | '=' | '+' | '-' | '<' | '>' | '.' | ',' | '[' | ']' => {
+                Token::Symbol(c)
+            }
+            c => return syntax(location, format!("unexpected character {:?}", c)),
+        };
+
+        tokens.push((token, location));
+    }
+
+    Ok(tokens)
+}
+
+/// Everything defined by a file and the files it includes
+#[derive(Default)]
+struct Definitions {
+    cells: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    statements: Vec<Statement>,
+    included: HashSet<PathBuf>,
+}
+
+struct Parser {
+    tokens: Vec<(Token, Location)>,
+    position: usize,
+    /// Where the end of the file is, for errors about it
+    end: Location,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn location(&self) -> Location {
+        self.tokens
+            .get(self.position)
+            .map_or_else(|| self.end.clone(), |(_, location)| location.clone())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), AssembleError> {
+        let location = self.location();
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            _ => syntax(location, format!("expected `{}`", symbol)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, AssembleError> {
+        let location = self.location();
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => syntax(location, "expected a name"),
+        }
+    }
+
+    /// Whether the next token is on the same line as the one before it
+    fn same_line(&self) -> bool {
+        match (self.tokens.get(self.position), self.position.checked_sub(1)) {
+            (Some((_, location)), Some(previous)) => location.line == self.tokens[previous].1.line,
+            _ => false,
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, AssembleError> {
+        let location = self.location();
+
+        let kind = match self.next() {
+            Some(Token::Name(name)) if name == "at" => StatementKind::At(self.name()?),
+            Some(Token::Name(name)) if ["cell", "macro", "include"].contains(&name.as_str()) => {
+                return syntax(
+                    location,
+                    format!("`{}` can't be used inside of macros", name),
+                )
+            }
+            Some(Token::Name(name)) => match self.next() {
+                Some(Token::Symbol(':')) => StatementKind::Select(name),
+                Some(Token::Symbol('(')) => {
+                    let mut args = Vec::new();
+
+                    if self.peek() == Some(&Token::Symbol(')')) {
+                        self.next();
+                    } else {
+                        loop {
+                            let location = self.location();
+                            let arg = match self.next() {
+                                Some(Token::Name(name)) => Arg::Name(name),
+                                Some(Token::Number(number)) => match u8::try_from(number) {
+                                    Ok(number) => Arg::Number(number),
+                                    Err(_) => return syntax(location, "numbers only go up to 255"),
+                                },
+                                _ => return syntax(location, "expected a cell or a number"),
+                            };
+                            args.push(arg);
+
+                            match self.next() {
+                                Some(Token::Symbol(',')) => (),
+                                Some(Token::Symbol(')')) => break,
+                                _ => return syntax(location, "expected `,` or `)`"),
+                            }
+                        }
+                    }
+
+                    StatementKind::Call(name, args)
+                }
+                _ => return syntax(location, format!("expected `:` or `(` after {}", name)),
+            },
+            Some(Token::Symbol(c)) => {
+                let instr = match c {
+                    '+' => Instruction::IncrementByte,
+                    '-' => Instruction::DecrementByte,
+                    '>' => Instruction::IncrementPointer,
+                    '<' => Instruction::DecrementPointer,
+                    '.' => Instruction::OutputByte,
+                    ',' => Instruction::ReadByte,
+                    '[' => Instruction::JumpForwardsIfZero,
+                    ']' => Instruction::JumpBackwardsIfNotZero,
+                    _ => return syntax(location, format!("unexpected `{}`", c)),
+                };
+
+                StatementKind::Raw(instr)
+            }
+            _ => return syntax(location, "expected a statement"),
+        };
+
+        Ok(Statement { kind, location })
+    }
+
+    fn file(&mut self, dir: &Path, definitions: &mut Definitions) -> Result<(), AssembleError> {
+        while let Some(token) = self.peek() {
+            let location = self.location();
+
+            match token {
+                Token::Name(keyword) if keyword == "include" => {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Text(path)) => include(&dir.join(path), definitions)?,
+                        _ => return syntax(location, "expected a path in quotes"),
+                    }
+                }
+                Token::Name(keyword) if keyword == "cell" => {
+                    self.next();
+
+                    loop {
+                        let location = self.location();
+                        let name = self.name()?;
+                        ensure!(
+                            !definitions.cells.contains_key(&name),
+                            Redefined { location, name }
+                        );
+
+                        let offset = if self.peek() == Some(&Token::Symbol('=')) {
+                            self.next();
+                            match self.next() {
+                                Some(Token::Number(offset)) => offset,
+                                _ => return syntax(location, "expected an offset"),
+                            }
+                        } else {
+                            (0..)
+                                .find(|offset| !definitions.cells.values().any(|o| o == offset))
+                                .expect("there's always a free cell")
+                        };
+
+                        definitions.cells.insert(name, offset);
+
+                        if !self.same_line() || !matches!(self.peek(), Some(Token::Name(_))) {
+                            break;
+                        }
+                    }
+                }
+                Token::Name(keyword) if keyword == "macro" => {
+                    self.next();
+                    let name = self.name()?;
+                    ensure!(
+                        !BUILTINS.contains(&name.as_str())
+                            && !definitions.macros.contains_key(&name),
+                        Redefined { location, name }
+                    );
+
+                    self.expect('(')?;
+                    let mut params = Vec::new();
+                    if self.peek() == Some(&Token::Symbol(')')) {
+                        self.next();
+                    } else {
+                        loop {
+                            params.push(self.name()?);
+                            let location = self.location();
+                            match self.next() {
+                                Some(Token::Symbol(',')) => (),
+                                Some(Token::Symbol(')')) => break,
+                                _ => return syntax(location, "expected `,` or `)`"),
+                            }
+                        }
+                    }
+
+                    self.expect('{')?;
+                    let mut body = Vec::new();
+                    while self.peek() != Some(&Token::Symbol('}')) {
+                        ensure!(
+                            self.peek().is_some(),
+                            Syntax {
+                                location: self.location(),
+                                message: "expected `}`",
+                            }
+                        );
+                        body.push(self.statement()?);
+                    }
+                    self.next();
+
+                    definitions.macros.insert(name, Macro { params, body });
+                }
+                _ => {
+                    let statement = self.statement()?;
+                    definitions.statements.push(statement);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a file into the definitions, unless it was already included
+fn include(path: &Path, definitions: &mut Definitions) -> Result<(), AssembleError> {
+    let canonical = fs::canonicalize(path).context(Read { path })?;
+    if !definitions.included.insert(canonical) {
+        return Ok(());
+    }
+
+    let source = fs::read_to_string(path).context(Read { path })?;
+    let tokens = tokenize(&source, path)?;
+    let end = Location {
+        file: path.to_path_buf(),
+        line: source.lines().count().max(1),
+    };
+
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end,
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    parser.file(dir, definitions)
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Value {
+    Cell(usize),
+    Number(u8),
+}
+
+struct Expander<'a> {
+    definitions: &'a Definitions,
+    code: Vec<Instruction>,
+    /// Where the data pointer is, unless a loop left it somewhere we can't know
+    pointer: Option<usize>,
+    /// Where the pointer was at each open bracket, and where the bracket is
+    loops: Vec<(Option<usize>, Location)>,
+    depth: usize,
+}
+
+impl Expander<'_> {
+    fn goto(&mut self, cell: usize, location: &Location) -> Result<(), AssembleError> {
+        let pointer = self.pointer.context(UnknownPosition {
+            location: location.clone(),
+        })?;
+
+        let instr = if cell > pointer {
+            Instruction::IncrementPointer
+        } else {
+            Instruction::DecrementPointer
+        };
+        self.code
+            .extend(std::iter::repeat_n(instr, cell.abs_diff(pointer)));
+        self.pointer = Some(cell);
+
+        Ok(())
+    }
+
+    fn emit(&mut self, instr: Instruction, location: &Location) -> Result<(), AssembleError> {
+        match instr {
+            Instruction::IncrementPointer => self.pointer = self.pointer.map(|p| p + 1),
+            Instruction::DecrementPointer => {
+                if let Some(pointer) = self.pointer {
+                    let pointer = pointer.checked_sub(1).context(OutOfBounds {
+                        location: location.clone(),
+                    })?;
+                    self.pointer = Some(pointer);
+                }
+            }
+            Instruction::JumpForwardsIfZero => self.loops.push((self.pointer, location.clone())),
+            Instruction::JumpBackwardsIfNotZero => {
+                let (start, _) = self.loops.pop().context(UnmatchedBracket {
+                    location: location.clone(),
+                })?;
+
+                // a loop that moves the pointer could end anywhere
+                if start != self.pointer {
+                    self.pointer = None;
+                }
+            }
+            _ => (),
+        }
+
+        self.code.push(instr);
+
+        Ok(())
+    }
+
+    fn add(&mut self, cell: usize, amount: u8, location: &Location) -> Result<(), AssembleError> {
+        self.goto(cell, location)?;
+
+        let instrs = if amount <= 128 {
+            std::iter::repeat_n(Instruction::IncrementByte, usize::from(amount))
+        } else {
+            std::iter::repeat_n(Instruction::DecrementByte, 256 - usize::from(amount))
+        };
+        self.code.extend(instrs);
+
+        Ok(())
+    }
+
+    fn clear(&mut self, cell: usize, location: &Location) -> Result<(), AssembleError> {
+        self.goto(cell, location)?;
+        self.code.extend_from_slice(&[
+            Instruction::JumpForwardsIfZero,
+            Instruction::DecrementByte,
+            Instruction::JumpBackwardsIfNotZero,
+        ]);
+
+        Ok(())
+    }
+
+    /// Adds a cell to others, zeroing it
+    fn move_into(
+        &mut self,
+        from: usize,
+        to: &[usize],
+        location: &Location,
+    ) -> Result<(), AssembleError> {
+        self.goto(from, location)?;
+        self.code.push(Instruction::JumpForwardsIfZero);
+        self.code.push(Instruction::DecrementByte);
+        for &cell in to {
+            self.add(cell, 1, location)?;
+        }
+        self.goto(from, location)?;
+        self.code.push(Instruction::JumpBackwardsIfNotZero);
+
+        Ok(())
+    }
+
+    fn builtin(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        location: &Location,
+    ) -> Result<(), AssembleError> {
+        use Value::{Cell, Number};
+
+        match (name, args) {
+            ("clear", &[Cell(a)]) => self.clear(a, location),
+            ("move", &[Cell(a), Cell(b)]) => self.move_into(a, &[b], location),
+            ("copy", &[Cell(a), Cell(b), Cell(tmp)]) => {
+                self.move_into(a, &[b, tmp], location)?;
+                self.move_into(tmp, &[a], location)
+            }
+            ("set", &[Cell(a), Number(n)]) => {
+                self.clear(a, location)?;
+                self.add(a, n, location)
+            }
+            ("add", &[Cell(a), Number(n)]) => self.add(a, n, location),
+            ("sub", &[Cell(a), Number(n)]) => self.add(a, n.wrapping_neg(), location),
+            _ => {
+                let expected = match name {
+                    "clear" => "(cell)",
+                    "move" => "(from, to)",
+                    "copy" => "(from, to, temporary cell)",
+                    _ => "(cell, number)",
+                };
+
+                WrongArguments {
+                    location: location.clone(),
+                    name,
+                    expected,
+                }
+                .fail()
+            }
+        }
+    }
+
+    fn resolve(
+        &self,
+        arg: &Arg,
+        bindings: &HashMap<&str, Value>,
+        location: &Location,
+    ) -> Result<Value, AssembleError> {
+        match arg {
+            Arg::Number(number) => Ok(Value::Number(*number)),
+            Arg::Name(name) => self.lookup(name, bindings, location),
+        }
+    }
+
+    /// Finds a macro parameter or a cell
+    fn lookup(
+        &self,
+        name: &str,
+        bindings: &HashMap<&str, Value>,
+        location: &Location,
+    ) -> Result<Value, AssembleError> {
+        match bindings.get(name) {
+            Some(&value) => Ok(value),
+            None => self
+                .definitions
+                .cells
+                .get(name)
+                .map(|&cell| Value::Cell(cell))
+                .context(UnknownCell {
+                    location: location.clone(),
+                    name,
+                }),
+        }
+    }
+
+    fn cell(
+        &self,
+        name: &str,
+        bindings: &HashMap<&str, Value>,
+        location: &Location,
+    ) -> Result<usize, AssembleError> {
+        match self.lookup(name, bindings, location)? {
+            Value::Cell(cell) => Ok(cell),
+            Value::Number(_) => syntax(
+                location.clone(),
+                format!("{} is a number, not a cell", name),
+            ),
+        }
+    }
+
+    fn statements(
+        &mut self,
+        statements: &[Statement],
+        bindings: &HashMap<&str, Value>,
+    ) -> Result<(), AssembleError> {
+        for statement in statements {
+            let location = &statement.location;
+
+            match &statement.kind {
+                StatementKind::Select(name) => {
+                    let cell = self.cell(name, bindings, location)?;
+                    self.goto(cell, location)?;
+                }
+                StatementKind::At(name) => {
+                    self.pointer = Some(self.cell(name, bindings, location)?);
+                }
+                StatementKind::Raw(instr) => self.emit(instr.clone(), location)?,
+                StatementKind::Call(name, args) => {
+                    let values = args
+                        .iter()
+                        .map(|arg| self.resolve(arg, bindings, location))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    if BUILTINS.contains(&name.as_str()) {
+                        self.builtin(name, &values, location)?;
+                        continue;
+                    }
+
+                    let definition = self.definitions.macros.get(name).context(UnknownMacro {
+                        location: location.clone(),
+                        name,
+                    })?;
+                    ensure!(
+                        definition.params.len() == values.len(),
+                        WrongArguments {
+                            location: location.clone(),
+                            name,
+                            expected: format!("{} arguments", definition.params.len()),
+                        }
+                    );
+                    ensure!(
+                        self.depth < MAX_DEPTH,
+                        TooDeep {
+                            location: location.clone(),
+                            name,
+                        }
+                    );
+
+                    let bindings = definition
+                        .params
+                        .iter()
+                        .map(String::as_str)
+                        .zip(values)
+                        .collect();
+
+                    self.depth += 1;
+                    self.statements(&definition.body, &bindings)?;
+                    self.depth -= 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Assembles a file, and everything it includes, into brainfuck
+pub fn assemble(path: &Path) -> Result<Vec<Instruction>, AssembleError> {
+    let mut definitions = Definitions::default();
+    include(path, &mut definitions)?;
+
+    let mut expander = Expander {
+        definitions: &definitions,
+        code: Vec::new(),
+        pointer: Some(0),
+        loops: Vec::new(),
+        depth: 0,
+    };
+    expander.statements(&definitions.statements, &HashMap::new())?;
+
+    if let Some((_, location)) = expander.loops.pop() {
+        return UnmatchedBracket { location }.fail();
+    }
+
+    Ok(expander.code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir;
+    use std::env;
+    use std::process;
+
+    /// Writes the files to a directory of their own and assembles the first one
+    fn assemble_files(test: &str, files: &[(&str, &str)]) -> Result<Vec<u8>, AssembleError> {
+        let dir = env::temp_dir().join(format!("bfr-bfasm-{}-{}", process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let assembled = assemble(&dir.join(files[0].0));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let program = ir::transform(&assembled?).unwrap();
+        let mut output = Vec::new();
+        ir::Vm::new(program)
+            .vm_loop(&mut io::empty(), &mut output)
+            .unwrap();
+
+        Ok(output)
+    }
+
+    #[test]
+    fn built_in_macros() {
+        let main = "
+            cell a b tmp
+            cell out = 5
+            a: +++++
+            copy(a, b, tmp)
+            set(out, 'A') add(out, 1) sub(out, 3)
+            out: . a: . b: .
+            move(b, a) a: . b: .
+            clear(a) a: .
+        ";
+
+        assert_eq!(
+            assemble_files("built_in_macros", &[("main.bfa", main)]).unwrap(),
+            [b'?', 5, 5, 10, 0, 0]
+        );
+    }
+
+    #[test]
+    fn macros_from_an_include_that_is_only_pulled_in_once() {
+        let lib = "
+            ; doubles x, using t
+            macro double(x, t) {
+                move(x, t)
+                t: [- x: ++ t:]
+            }
+        ";
+        let main = "
+            include \"lib.bfa\"
+            include \"./lib.bfa\"
+            cell a tmp
+            a: +++
+            double(a, tmp) double(a, tmp)
+            a: .
+        ";
+
+        assert_eq!(
+            assemble_files("includes", &[("main.bfa", main), ("lib.bfa", lib)]).unwrap(),
+            [12]
+        );
+    }
+
+    #[test]
+    fn errors_say_where() {
+        let unknown_cell = assemble_files("unknown_cell", &[("main.bfa", "cell a\n\nb: +\n")]);
+        assert!(matches!(
+            unknown_cell,
+            Err(AssembleError::UnknownCell { ref location, ref name })
+                if location.line == 3 && name == "b"
+        ));
+
+        let unknown_macro = assemble_files("unknown_macro", &[("main.bfa", "cell a\nfoo(a)\n")]);
+        assert!(matches!(
+            unknown_macro,
+            Err(AssembleError::UnknownMacro { ref location, ref name })
+                if location.line == 2 && name == "foo"
+        ));
+
+        let lost = assemble_files("lost", &[("main.bfa", "cell a\na: [>+] a: +\n")]);
+        assert!(matches!(lost, Err(AssembleError::UnknownPosition { .. })));
+
+        let left = assemble_files("left", &[("main.bfa", "cell a = 1\na: <<\n")]);
+        assert!(matches!(left, Err(AssembleError::OutOfBounds { .. })));
+
+        let missing = assemble_files("missing", &[("main.bfa", "include \"nope.bfa\"\n")]);
+        assert!(matches!(missing, Err(AssembleError::Read { .. })));
+    }
+}
@@ -0,0 +1,506 @@
+/// Lowers a BFL syntax tree into brainfuck
+///
+/// The position of the data pointer is always known while compiling, as every loop ends on the
+/// same cell it starts on. Temporaries are handed out zeroed and must be zeroed before being
+/// freed, so the allocator can hand them out again without clearing them
+use super::parse::{BinaryOp, Expr, PrintItem, Statement};
+use super::CompileError;
+use crate::brainfuck::Instruction;
+
+struct Compiler {
+    code: Vec<Instruction>,
+    pointer: usize,
+    used: Vec<bool>,
+    scopes: Vec<Vec<(String, usize)>>,
+}
+
+impl Compiler {
+    fn alloc(&mut self) -> usize {
+        match self.used.iter().position(|&used| !used) {
+            Some(cell) => {
+                self.used[cell] = true;
+                cell
+            }
+            None => {
+                self.used.push(true);
+                self.used.len() - 1
+            }
+        }
+    }
+
+    /// Gives a cell back to the allocator, which must be zero by now
+    fn free(&mut self, cell: usize) {
+        self.used[cell] = false;
+    }
+
+    fn goto(&mut self, cell: usize) {
+        let instr = if cell > self.pointer {
+            Instruction::IncrementPointer
+        } else {
+            Instruction::DecrementPointer
+        };
+
+        self.code
+            .extend(std::iter::repeat_n(instr, cell.abs_diff(self.pointer)));
+        self.pointer = cell;
+    }
+
+    /// Adds to a cell, going whichever way around is shorter
+    fn add(&mut self, cell: usize, amount: i32) {
+        self.goto(cell);
+
+        let amount = amount.rem_euclid(256) as usize;
+        if amount <= 128 {
+            self.code
+                .extend(std::iter::repeat_n(Instruction::IncrementByte, amount));
+        } else {
+            self.code.extend(std::iter::repeat_n(
+                Instruction::DecrementByte,
+                256 - amount,
+            ));
+        }
+    }
+
+    fn clear(&mut self, cell: usize) {
+        self.goto(cell);
+        self.code.extend_from_slice(&[
+            Instruction::JumpForwardsIfZero,
+            Instruction::DecrementByte,
+            Instruction::JumpBackwardsIfNotZero,
+        ]);
+    }
+
+    /// Runs some code while a cell isn't zero
+    fn repeat_while<T>(&mut self, cell: usize, body: impl FnOnce(&mut Self) -> T) -> T {
+        self.goto(cell);
+        self.code.push(Instruction::JumpForwardsIfZero);
+        let result = body(self);
+        self.goto(cell);
+        self.code.push(Instruction::JumpBackwardsIfNotZero);
+
+        result
+    }
+
+    /// Adds a cell to others, each multiplied by some factor, leaving the cell zeroed
+    fn move_into(&mut self, from: usize, to: &[(usize, i32)]) {
+        self.repeat_while(from, |c| {
+            c.add(from, -1);
+            for &(cell, factor) in to {
+                c.add(cell, factor);
+            }
+        });
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        let temporary = self.alloc();
+        self.move_into(from, &[(to, 1), (temporary, 1)]);
+        self.move_into(temporary, &[(from, 1)]);
+        self.free(temporary);
+    }
+
+    /// Runs one of two branches depending on whether a temporary is zero, consuming it
+    fn if_else(
+        &mut self,
+        condition: usize,
+        then: impl FnOnce(&mut Self) -> Result<(), CompileError>,
+        otherwise: impl FnOnce(&mut Self) -> Result<(), CompileError>,
+    ) -> Result<(), CompileError> {
+        let run_otherwise = self.alloc();
+        self.add(run_otherwise, 1);
+
+        self.repeat_while(condition, |c| {
+            c.clear(condition);
+            c.add(run_otherwise, -1);
+            then(c)
+        })?;
+        self.repeat_while(run_otherwise, |c| {
+            c.add(run_otherwise, -1);
+            otherwise(c)
+        })?;
+
+        self.free(condition);
+        self.free(run_otherwise);
+
+        Ok(())
+    }
+
+    /// 1 if a temporary is zero and 0 otherwise, consuming it
+    fn not(&mut self, value: usize) -> usize {
+        let result = self.alloc();
+        self.add(result, 1);
+        self.repeat_while(value, |c| {
+            c.clear(value);
+            c.add(result, -1);
+        });
+        self.free(value);
+
+        result
+    }
+
+    /// 0 if a temporary is zero and 1 otherwise, consuming it
+    fn bool(&mut self, value: usize) -> usize {
+        let result = self.alloc();
+        self.repeat_while(value, |c| {
+            c.clear(value);
+            c.add(result, 1);
+        });
+        self.free(value);
+
+        result
+    }
+
+    /// Whether a < b, consuming both
+    ///
+    /// Counts b down to zero while counting a down alongside it, a running out first means it was smaller
+    fn less(&mut self, a: usize, b: usize) -> usize {
+        let result = self.alloc();
+
+        self.repeat_while(b, |c| {
+            c.add(b, -1);
+            let a_left = c.alloc();
+            c.copy(a, a_left);
+            c.if_else(
+                a_left,
+                |c| {
+                    c.add(a, -1);
+                    Ok(())
+                },
+                |c| {
+                    c.add(result, 1);
+                    c.clear(b);
+                    Ok(())
+                },
+            )
+            .expect("branches can't fail");
+        });
+
+        self.clear(a);
+        self.free(a);
+        self.free(b);
+
+        result
+    }
+
+    /// Divides a by b, consuming both, and returns the quotient and the remainder
+    fn divide(&mut self, a: usize, b: usize) -> (usize, usize) {
+        let quotient = self.alloc();
+        let remainder = self.alloc();
+
+        self.repeat_while(a, |c| {
+            c.add(a, -1);
+            c.add(remainder, 1);
+
+            let left = c.alloc();
+            let right = c.alloc();
+            c.copy(remainder, left);
+            c.copy(b, right);
+            let equal = c.equal(left, right);
+
+            c.if_else(
+                equal,
+                |c| {
+                    c.clear(remainder);
+                    c.add(quotient, 1);
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .expect("branches can't fail");
+        });
+
+        self.clear(b);
+        self.free(a);
+        self.free(b);
+
+        (quotient, remainder)
+    }
+
+    fn equal(&mut self, a: usize, b: usize) -> usize {
+        self.move_into(b, &[(a, -1)]);
+        self.free(b);
+        self.not(a)
+    }
+
+    fn lookup(&self, name: &str, line: usize) -> Result<usize, CompileError> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter())
+            .find(|(declared, _)| declared == name)
+            .map(|&(_, cell)| cell)
+            .ok_or_else(|| CompileError::Undeclared {
+                name: name.to_string(),
+                line,
+            })
+    }
+
+    /// Evaluates an expression into a new temporary
+    fn expression(&mut self, expr: &Expr) -> Result<usize, CompileError> {
+        let cell = match expr {
+            Expr::Number(number) => {
+                let cell = self.alloc();
+                self.add(cell, i32::from(*number));
+                cell
+            }
+            Expr::Variable { name, line } => {
+                let variable = self.lookup(name, *line)?;
+                let cell = self.alloc();
+                self.copy(variable, cell);
+                cell
+            }
+            Expr::Negate(value) => {
+                let value = self.expression(value)?;
+                let cell = self.alloc();
+                self.move_into(value, &[(cell, -1)]);
+                self.free(value);
+                cell
+            }
+            Expr::Not(value) => {
+                let value = self.expression(value)?;
+                self.not(value)
+            }
+            Expr::Binary(left, op, right) => {
+                let a = self.expression(left)?;
+                let b = self.expression(right)?;
+                self.binary(a, *op, b)
+            }
+        };
+
+        Ok(cell)
+    }
+
+    fn binary(&mut self, a: usize, op: BinaryOp, b: usize) -> usize {
+        match op {
+            BinaryOp::Add | BinaryOp::Subtract => {
+                let factor = if op == BinaryOp::Add { 1 } else { -1 };
+                self.move_into(b, &[(a, factor)]);
+                self.free(b);
+                a
+            }
+            BinaryOp::Multiply => {
+                let result = self.alloc();
+                self.repeat_while(a, |c| {
+                    c.add(a, -1);
+                    c.copy(b, result);
+                });
+                self.clear(b);
+                self.free(a);
+                self.free(b);
+                result
+            }
+            BinaryOp::Divide | BinaryOp::Remainder => {
+                let (quotient, remainder) = self.divide(a, b);
+                let (result, unused) = if op == BinaryOp::Divide {
+                    (quotient, remainder)
+                } else {
+                    (remainder, quotient)
+                };
+
+                self.clear(unused);
+                self.free(unused);
+                result
+            }
+            BinaryOp::Equal => self.equal(a, b),
+            BinaryOp::NotEqual => {
+                self.move_into(b, &[(a, -1)]);
+                self.free(b);
+                self.bool(a)
+            }
+            BinaryOp::Less => self.less(a, b),
+            BinaryOp::Greater => self.less(b, a),
+            BinaryOp::LessOrEqual => {
+                let greater = self.less(b, a);
+                self.not(greater)
+            }
+            BinaryOp::GreaterOrEqual => {
+                let less = self.less(a, b);
+                self.not(less)
+            }
+            BinaryOp::And | BinaryOp::Or => {
+                // neither side has side effects, so there's nothing to short circuit
+                let a = self.bool(a);
+                let b = self.bool(b);
+                self.move_into(b, &[(a, 1)]);
+                self.free(b);
+
+                if op == BinaryOp::And {
+                    self.add(a, -2);
+                    self.not(a)
+                } else {
+                    self.bool(a)
+                }
+            }
+        }
+    }
+
+    fn print_digit(&mut self, digit: usize) {
+        self.add(digit, i32::from(b'0'));
+        self.code.push(Instruction::OutputByte);
+        self.add(digit, -i32::from(b'0'));
+    }
+
+    /// Prints a temporary as a decimal number, consuming it
+    fn print_number(&mut self, value: usize) {
+        let hundred = self.alloc();
+        self.add(hundred, 100);
+        let (hundreds, rest) = self.divide(value, hundred);
+
+        let ten = self.alloc();
+        self.add(ten, 10);
+        let (tens, ones) = self.divide(rest, ten);
+
+        // leading zeros are skipped, but a zero in the middle isn't
+        let has_hundreds = self.alloc();
+        self.copy(hundreds, has_hundreds);
+        self.if_else(
+            has_hundreds,
+            |c| {
+                c.print_digit(hundreds);
+                c.print_digit(tens);
+                Ok(())
+            },
+            |c| {
+                let has_tens = c.alloc();
+                c.copy(tens, has_tens);
+                c.if_else(
+                    has_tens,
+                    |c| {
+                        c.print_digit(tens);
+                        Ok(())
+                    },
+                    |_| Ok(()),
+                )
+            },
+        )
+        .expect("branches can't fail");
+        self.print_digit(ones);
+
+        for cell in [hundreds, tens, ones] {
+            self.clear(cell);
+            self.free(cell);
+        }
+    }
+
+    fn print_text(&mut self, text: &[u8]) {
+        let cell = self.alloc();
+        let mut value = 0;
+
+        for &byte in text {
+            self.add(cell, i32::from(byte) - value);
+            self.code.push(Instruction::OutputByte);
+            value = i32::from(byte);
+        }
+
+        self.add(cell, -value);
+        self.free(cell);
+    }
+
+    fn block(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        self.scopes.push(Vec::new());
+
+        for statement in statements {
+            self.statement(statement)?;
+        }
+
+        let scope = self.scopes.pop().expect("a scope was just pushed");
+        for (_, cell) in scope {
+            self.clear(cell);
+            self.free(cell);
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match statement {
+            Statement::Let { name, value, line } => {
+                if self.lookup(name, *line).is_ok() {
+                    return Err(CompileError::AlreadyDeclared {
+                        name: name.clone(),
+                        line: *line,
+                    });
+                }
+
+                // the temporary holding the value just becomes the variable
+                let cell = self.expression(value)?;
+                self.scopes
+                    .last_mut()
+                    .expect("there's always a scope")
+                    .push((name.clone(), cell));
+            }
+            Statement::Assign { name, value, line } => {
+                let variable = self.lookup(name, *line)?;
+                let value = self.expression(value)?;
+                self.clear(variable);
+                self.move_into(value, &[(variable, 1)]);
+                self.free(value);
+            }
+            Statement::While(condition, body) => {
+                let keep_going = self.expression(condition)?;
+                self.repeat_while(keep_going, |c| {
+                    c.clear(keep_going);
+                    c.block(body)?;
+
+                    let value = c.expression(condition)?;
+                    c.move_into(value, &[(keep_going, 1)]);
+                    c.free(value);
+
+                    Ok(())
+                })?;
+                self.free(keep_going);
+            }
+            Statement::If(condition, then, otherwise) => {
+                let condition = self.expression(condition)?;
+
+                if otherwise.is_empty() {
+                    self.repeat_while(condition, |c| {
+                        c.clear(condition);
+                        c.block(then)
+                    })?;
+                    self.free(condition);
+                } else {
+                    self.if_else(condition, |c| c.block(then), |c| c.block(otherwise))?;
+                }
+            }
+            Statement::Print(items) => {
+                for item in items {
+                    match item {
+                        PrintItem::Text(text) => self.print_text(text),
+                        PrintItem::Number(expr) => {
+                            let value = self.expression(expr)?;
+                            self.print_number(value);
+                        }
+                    }
+                }
+            }
+            Statement::Put(value) => {
+                let value = self.expression(value)?;
+                self.goto(value);
+                self.code.push(Instruction::OutputByte);
+                self.clear(value);
+                self.free(value);
+            }
+            Statement::Read { name, line } => {
+                // reads at the end of input usually leave the cell alone, which makes them 0 here
+                let variable = self.lookup(name, *line)?;
+                self.clear(variable);
+                self.code.push(Instruction::ReadByte);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn lower(statements: &[Statement]) -> Result<Vec<Instruction>, CompileError> {
+    let mut compiler = Compiler {
+        code: Vec::new(),
+        pointer: 0,
+        used: Vec::new(),
+        scopes: Vec::new(),
+    };
+
+    compiler.block(statements)?;
+
+    Ok(compiler.code)
+}
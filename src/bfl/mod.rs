@@ -0,0 +1,122 @@
+#![forbid(unsafe_code)]
+
+/// BFL, a small structured language that compiles down to brainfuck
+///
+/// Every value is a byte, and arithmetic wraps around like brainfuck cells do. A program is a list
+/// of statements:
+/// ```text
+/// let x = 6 * 7;          // declares a variable, visible until the end of its block
+/// x = x - 2;              // assigns to a variable declared before
+/// while x > 0 { ... }     // loops while an expression isn't zero
+/// if x == 1 { ... } else if x < 10 { ... } else { ... }
+/// print "x is ", x, "\n"; // prints text as is, and expressions as decimal numbers
+/// put 'a';                // prints an expression as a raw byte
+/// read x;                 // reads a byte of input into x, 0 at the end of input
+/// ```
+/// Expressions are numbers up to 255, character literals, variables and parentheses, combined
+/// with `+ - * / %`, the comparisons `== != < <= > >=`, `&&`, `||` and the unary `-` and `!`.
+/// Comparisons and logic result in 0 or 1, `x / 0` is 0 and `x % 0` is x
+///
+/// `read` clears the variable before reading into it, so it's the vm leaving the cell alone at
+/// the end of input that makes it 0 there. With `--eof minus-one` it's 255 instead
+///
+/// Each variable and temporary value gets its own cell, handed out by an allocator that reuses
+/// cells once they're freed and zeroed again
+use snafu::Snafu;
+
+use crate::brainfuck::Instruction;
+
+mod lower;
+mod parse;
+
+#[derive(Snafu, Debug)]
+pub enum CompileError {
+    #[snafu(display("Syntax error on line {}: {}", line, message))]
+    Syntax { line: usize, message: String },
+    #[snafu(display("Variable {} on line {} isn't declared", name, line))]
+    Undeclared { name: String, line: usize },
+    #[snafu(display("Variable {} on line {} is already declared", name, line))]
+    AlreadyDeclared { name: String, line: usize },
+}
+
+/// Compiles BFL source code into brainfuck
+pub fn compile(source: &str) -> Result<Vec<Instruction>, CompileError> {
+    let statements = parse::parse(source)?;
+    lower::lower(&statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir;
+
+    fn run(source: &str, input: &[u8]) -> String {
+        let program = ir::transform(&compile(source).unwrap()).unwrap();
+        let mut output = Vec::new();
+
+        ir::Vm::new(program)
+            .vm_loop(&mut &input[..], &mut output)
+            .unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn arithmetic_wraps_like_cells() {
+        let source = r#"
+            let x = 6 * 7;
+            print x, " ", 200 + 100, " ", 7 / 2, " ", 7 % 3, " ", -1;
+        "#;
+
+        assert_eq!(run(source, b""), "42 44 3 1 255");
+    }
+
+    #[test]
+    fn dividing_by_zero_doesnt_trap() {
+        assert_eq!(run(r#"print 7 / 0, " ", 7 % 0;"#, b""), "0 7");
+    }
+
+    #[test]
+    fn comparisons_and_logic_are_0_or_1() {
+        let source = "print 3 < 4, 4 <= 3, 2 == 2, 2 != 2, 1 && 0, 1 || 0, !5;";
+
+        assert_eq!(run(source, b""), "1010010");
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let source = r#"
+            let n = 0;
+            while n < 5 {
+                if n == 2 { put 'x'; } else if n > 3 { put 'y'; } else { put '.'; }
+                n = n + 1;
+            }
+        "#;
+
+        assert_eq!(run(source, b""), "..x.y");
+    }
+
+    #[test]
+    fn reads_are_0_at_the_end_of_input() {
+        assert_eq!(
+            run("let n = 9; read n; print n; read n; print n;", b"A"),
+            "650"
+        );
+    }
+
+    #[test]
+    fn errors_say_which_line() {
+        assert!(matches!(
+            compile("let y = 1;\nprint z;"),
+            Err(CompileError::Undeclared { ref name, line: 2 }) if name == "z"
+        ));
+        assert!(matches!(
+            compile("let y = 1;\nlet y = 2;"),
+            Err(CompileError::AlreadyDeclared { ref name, line: 2 }) if name == "y"
+        ));
+        assert!(matches!(
+            compile("let y = 1;\n\ny = ;"),
+            Err(CompileError::Syntax { line: 3, .. })
+        ));
+    }
+}
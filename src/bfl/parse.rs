@@ -0,0 +1,450 @@
+/// Turns BFL source code into a syntax tree
+use std::convert::TryFrom;
+
+use super::CompileError;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(u8),
+    Variable { name: String, line: usize },
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrintItem {
+    Text(Vec<u8>),
+    /// Printed as a decimal number
+    Number(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Let {
+        name: String,
+        value: Expr,
+        line: usize,
+    },
+    Assign {
+        name: String,
+        value: Expr,
+        line: usize,
+    },
+    While(Expr, Vec<Statement>),
+    If(Expr, Vec<Statement>, Vec<Statement>),
+    Print(Vec<PrintItem>),
+    Put(Expr),
+    Read {
+        name: String,
+        line: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(u32),
+    Text(Vec<u8>),
+    Symbol(&'static str),
+}
+
+// longer symbols first, so `==` isn't read as two `=`
+const SYMBOLS: [&str; 22] = [
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ";", ",", "=", "<", ">", "+", "-", "*",
+    "/", "%", "!", "'",
+];
+
+fn syntax<T>(line: usize, message: impl Into<String>) -> Result<T, CompileError> {
+    Err(CompileError::Syntax {
+        line,
+        message: message.into(),
+    })
+}
+
+/// Reads an escape sequence after a backslash, like \n, \" or \x41
+fn escape(
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    line: usize,
+) -> Result<u8, CompileError> {
+    let byte = match chars.next().map(|(_, c)| c) {
+        Some('n') => b'\n',
+        Some('t') => b'\t',
+        Some('r') => b'\r',
+        Some('0') => 0,
+        Some('\\') => b'\\',
+        Some('"') => b'"',
+        Some('\'') => b'\'',
+        Some('x') => {
+            let digits: String = (0..2)
+                .filter_map(|_| chars.next().map(|(_, c)| c))
+                .collect();
+            match u8::from_str_radix(&digits, 16) {
+                Ok(byte) if digits.len() == 2 => byte,
+                _ => return syntax(line, "\\x needs two hex digits"),
+            }
+        }
+        _ => return syntax(line, "unknown escape sequence"),
+    };
+
+    Ok(byte)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    let mut line = 1;
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c == '\n' {
+            line += 1;
+            chars.next();
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if source[start..].starts_with("//") {
+            while chars.peek().is_some_and(|&(_, c)| c != '\n') {
+                chars.next();
+            }
+        } else if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some(&(idx, c)) = chars.peek() {
+                if !c.is_ascii_digit() {
+                    break;
+                }
+                end = idx + 1;
+                chars.next();
+            }
+
+            match source[start..end].parse() {
+                Ok(number) => tokens.push((Token::Number(number), line)),
+                Err(_) => return syntax(line, "number is too big"),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(idx, c)) = chars.peek() {
+                if !c.is_alphanumeric() && c != '_' {
+                    break;
+                }
+                end = idx + c.len_utf8();
+                chars.next();
+            }
+
+            tokens.push((Token::Identifier(source[start..end].to_string()), line));
+        } else if c == '"' {
+            chars.next();
+            let mut text = Vec::new();
+
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => text.push(escape(&mut chars, line)?),
+                    Some((_, '\n')) | None => return syntax(line, "unterminated string"),
+                    Some((_, c)) => {
+                        let mut buffer = [0; 4];
+                        text.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                    }
+                }
+            }
+
+            tokens.push((Token::Text(text), line));
+        } else if c == '\'' {
+            // character literals are just numbers
+            chars.next();
+            let byte = match chars.next() {
+                Some((_, '\\')) => escape(&mut chars, line)?,
+                Some((_, c)) if c.is_ascii() && c != '\'' => c as u8,
+                _ => return syntax(line, "character literals must be a single ASCII character"),
+            };
+
+            if chars.next().map(|(_, c)| c) != Some('\'') {
+                return syntax(line, "unterminated character literal");
+            }
+
+            tokens.push((Token::Number(u32::from(byte)), line));
+        } else {
+            match SYMBOLS
+                .iter()
+                .find(|symbol| source[start..].starts_with(*symbol))
+            {
+                Some(symbol) => {
+                    for _ in 0..symbol.len() {
+                        chars.next();
+                    }
+                    tokens.push((Token::Symbol(symbol), line));
+                }
+                None => return syntax(line, format!("unexpected character {:?}", c)),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |&(_, line)| line)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if self.peek()
+            == Some(&Token::Symbol(
+                SYMBOLS.iter().find(|s| **s == symbol).unwrap(),
+            ))
+        {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            syntax(self.line(), format!("expected `{}`", symbol))
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, CompileError> {
+        match self.next() {
+            Some(Token::Identifier(name)) if !is_keyword(&name) => Ok(name),
+            _ => {
+                self.position -= 1;
+                syntax(self.line(), "expected a variable name")
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, CompileError> {
+        self.expect("{")?;
+
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            if self.peek().is_none() {
+                return syntax(self.line(), "expected `}`");
+            }
+
+            statements.push(self.statement()?);
+        }
+
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let line = self.line();
+
+        let keyword = match self.peek() {
+            Some(Token::Identifier(name)) if is_keyword(name) => name.clone(),
+            Some(Token::Identifier(_)) => {
+                let name = self.identifier()?;
+                self.expect("=")?;
+                let value = self.expression()?;
+                self.expect(";")?;
+
+                return Ok(Statement::Assign { name, value, line });
+            }
+            _ => return syntax(line, "expected a statement"),
+        };
+        self.position += 1;
+
+        let statement = match keyword.as_str() {
+            "let" => {
+                let name = self.identifier()?;
+                self.expect("=")?;
+                let value = self.expression()?;
+                self.expect(";")?;
+
+                Statement::Let { name, value, line }
+            }
+            "while" => {
+                let condition = self.expression()?;
+                Statement::While(condition, self.block()?)
+            }
+            "if" => {
+                let condition = self.expression()?;
+                let then = self.block()?;
+
+                let otherwise = if self.peek() == Some(&Token::Identifier(String::from("else"))) {
+                    self.position += 1;
+
+                    // else if is just an if inside of the else block
+                    if self.peek() == Some(&Token::Identifier(String::from("if"))) {
+                        vec![self.statement()?]
+                    } else {
+                        self.block()?
+                    }
+                } else {
+                    Vec::new()
+                };
+
+                Statement::If(condition, then, otherwise)
+            }
+            "print" => {
+                let mut items = Vec::new();
+
+                loop {
+                    let item = match self.peek() {
+                        Some(Token::Text(text)) => {
+                            let text = text.clone();
+                            self.position += 1;
+                            PrintItem::Text(text)
+                        }
+                        _ => PrintItem::Number(self.expression()?),
+                    };
+                    items.push(item);
+
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+
+                self.expect(";")?;
+                Statement::Print(items)
+            }
+            "put" => {
+                let value = self.expression()?;
+                self.expect(";")?;
+                Statement::Put(value)
+            }
+            "read" => {
+                let name = self.identifier()?;
+                self.expect(";")?;
+                Statement::Read { name, line }
+            }
+            _ => return syntax(line, format!("unexpected `{}`", keyword)),
+        };
+
+        Ok(statement)
+    }
+
+    fn expression(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+
+    /// Parses binary operators with at least the given precedence
+    fn binary(&mut self, precedence: usize) -> Result<Expr, CompileError> {
+        const LEVELS: [&[(&str, BinaryOp)]; 5] = [
+            &[("||", BinaryOp::Or)],
+            &[("&&", BinaryOp::And)],
+            &[
+                ("==", BinaryOp::Equal),
+                ("!=", BinaryOp::NotEqual),
+                ("<=", BinaryOp::LessOrEqual),
+                (">=", BinaryOp::GreaterOrEqual),
+                ("<", BinaryOp::Less),
+                (">", BinaryOp::Greater),
+            ],
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+            &[
+                ("*", BinaryOp::Multiply),
+                ("/", BinaryOp::Divide),
+                ("%", BinaryOp::Remainder),
+            ],
+        ];
+
+        if precedence == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(precedence + 1)?;
+
+        'operators: loop {
+            for &(symbol, op) in LEVELS[precedence] {
+                if self.eat(symbol) {
+                    let right = self.binary(precedence + 1)?;
+                    left = Expr::Binary(Box::new(left), op, Box::new(right));
+                    continue 'operators;
+                }
+            }
+
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        if self.eat("-") {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, CompileError> {
+        let line = self.line();
+
+        match self.next() {
+            Some(Token::Number(number)) => match u8::try_from(number) {
+                Ok(number) => Ok(Expr::Number(number)),
+                Err(_) => syntax(line, "numbers only go up to 255"),
+            },
+            Some(Token::Identifier(name)) if !is_keyword(&name) => {
+                Ok(Expr::Variable { name, line })
+            }
+            Some(Token::Symbol("(")) => {
+                let expr = self.expression()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => syntax(line, "expected an expression"),
+        }
+    }
+}
+
+fn is_keyword(name: &str) -> bool {
+    matches!(
+        name,
+        "let" | "while" | "if" | "else" | "print" | "put" | "read"
+    )
+}
+
+pub fn parse(source: &str) -> Result<Vec<Statement>, CompileError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+
+    let mut statements = Vec::new();
+    while parser.peek().is_some() {
+        statements.push(parser.statement()?);
+    }
+
+    Ok(statements)
+}
@@ -27,10 +27,11 @@ pub enum Instruction {
 
 /// Parses a stream of bytes (assumed to be brainfuck source code) into a Vec of Brainfuck instructions
 ///
-/// Does no optimizations at all
+/// Does no optimizations at all. Stops at the first `!`, as anything after it is input for the program
 pub fn parse(stream: impl IntoIterator<Item = u8>) -> Vec<Instruction> {
     stream
         .into_iter()
+        .take_while(|&byte| byte != b'!')
         .filter_map(|byte| match byte {
             b'>' => Some(Instruction::IncrementPointer),
             b'<' => Some(Instruction::DecrementPointer),
@@ -45,6 +46,31 @@ pub fn parse(stream: impl IntoIterator<Item = u8>) -> Vec<Instruction> {
         .collect()
 }
 
+/// Splits a program from the input it carries after a `!`, if there's any
+pub fn split_input(source: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match source.iter().position(|&byte| byte == b'!') {
+        Some(idx) => (&source[..idx], Some(&source[idx + 1..])),
+        None => (source, None),
+    }
+}
+
+/// Turns brainfuck instructions back into source code
+pub fn unparse(instructions: &[Instruction]) -> Vec<u8> {
+    instructions
+        .iter()
+        .map(|instr| match instr {
+            Instruction::IncrementPointer => b'>',
+            Instruction::DecrementPointer => b'<',
+            Instruction::IncrementByte => b'+',
+            Instruction::DecrementByte => b'-',
+            Instruction::OutputByte => b'.',
+            Instruction::ReadByte => b',',
+            Instruction::JumpForwardsIfZero => b'[',
+            Instruction::JumpBackwardsIfNotZero => b']',
+        })
+        .collect()
+}
+
 /// A pure Brainfuck virtual machine
 ///
 /// Does no optimizations and is probably as slow as it gets
@@ -119,7 +145,7 @@ impl Vm {
             }
             Instruction::ReadByte => {
                 input
-                    .read(&mut self.cells[self.data_pointer..1])
+                    .read(&mut self.cells[self.data_pointer..=self.data_pointer])
                     .context(FailedToRead)?;
                 self.program_counter += 1;
             }
@@ -202,3 +228,19 @@ impl Vm {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_past_cell_0_and_leaves_the_cell_alone_at_the_end_of_input() {
+        let mut output = Vec::new();
+
+        Vm::new(parse(b">,.>+,.,.".iter().copied()))
+            .vm_loop(&mut &b"a"[..], &mut output)
+            .unwrap();
+
+        assert_eq!(output, [b'a', 1, 1]);
+    }
+}
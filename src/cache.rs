@@ -0,0 +1,398 @@
+#![forbid(unsafe_code)]
+
+/// An on-disk cache of transformed and assembled programs, so repeated runs can skip straight to
+/// executing
+///
+/// Entries are stored in a small binary format, all integers little endian:
+/// ```text
+/// magic "BFRC" | format version: u32 | instruction count: u64 | instructions
+///              | has assembly: u8 | code length: u64 | code | source map length: u64 | source map
+///              | checksum of everything before it: u64
+/// ```
+/// Each instruction is a tag byte followed by its operands, as i32 for increments and u64 for jumps
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use crate::ir::Instruction;
+use crate::jit::Assembly;
+
+const MAGIC: &[u8; 4] = b"BFRC";
+
+/// Bumped whenever the format, or the code the JIT generates, changes
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Snafu, Debug)]
+pub enum CacheError {
+    #[snafu(display("Failed to read or write the cache: {}", source))]
+    Io { source: io::Error },
+    #[snafu(display("Not a cache entry"))]
+    BadMagic,
+    #[snafu(display(
+        "Cache entry has format version {}, expected {}",
+        version,
+        FORMAT_VERSION
+    ))]
+    UnsupportedVersion { version: u32 },
+    #[snafu(display("Cache entry is truncated"))]
+    Truncated,
+    #[snafu(display("Cache entry is corrupted"))]
+    Corrupted,
+    #[snafu(display("Unknown instruction tag {}", tag))]
+    UnknownInstruction { tag: u8 },
+}
+
+/// A program ready to be interpreted, and maybe its JIT compiled code
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub instructions: Vec<Instruction>,
+    pub assembly: Option<Assembly>,
+}
+
+// FNV-1a, which is simple enough to be stable across rust versions, unlike std's hashers
+fn hash(data: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+
+    for bytes in data {
+        for &byte in *bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    hash
+}
+
+/// Identifies a program compiled with some set of options
+pub fn key(source: &[u8], options: &str) -> u64 {
+    hash(&[
+        &FORMAT_VERSION.to_le_bytes(),
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        &(options.len() as u64).to_le_bytes(),
+        options.as_bytes(),
+        source,
+    ])
+}
+
+fn push_u64(buffer: &mut Vec<u8>, value: usize) {
+    buffer.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+pub fn encode(entry: &Entry) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+    push_u64(&mut buffer, entry.instructions.len());
+    for instr in &entry.instructions {
+        match *instr {
+            Instruction::IncrementPointer(inc) => {
+                buffer.push(0);
+                buffer.extend_from_slice(&inc.to_le_bytes());
+            }
+            Instruction::IncrementByte(inc) => {
+                buffer.push(1);
+                buffer.extend_from_slice(&inc.to_le_bytes());
+            }
+            Instruction::IncrementPointerAndByte(pointer_inc, byte_inc) => {
+                buffer.push(2);
+                buffer.extend_from_slice(&pointer_inc.to_le_bytes());
+                buffer.extend_from_slice(&byte_inc.to_le_bytes());
+            }
+            Instruction::OutputByte => buffer.push(3),
+            Instruction::ReadByte => buffer.push(4),
+            Instruction::JumpForwardsIfZero(jmp) => {
+                buffer.push(5);
+                push_u64(&mut buffer, jmp);
+            }
+            Instruction::JumpBackwardsIfNotZero(jmp) => {
+                buffer.push(6);
+                push_u64(&mut buffer, jmp);
+            }
+        }
+    }
+
+    match &entry.assembly {
+        Some(assembly) => {
+            buffer.push(1);
+            push_u64(&mut buffer, assembly.code.len());
+            buffer.extend_from_slice(&assembly.code);
+            push_u64(&mut buffer, assembly.source_map.len());
+            for &offset in &assembly.source_map {
+                push_u64(&mut buffer, offset);
+            }
+        }
+        None => buffer.push(0),
+    }
+
+    let checksum = hash(&[&buffer]);
+    buffer.extend_from_slice(&checksum.to_le_bytes());
+
+    buffer
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CacheError> {
+        ensure!(len <= self.data.len(), Truncated);
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, CacheError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, CacheError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, CacheError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, CacheError> {
+        let value = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(value).ok().context(Corrupted)
+    }
+
+    // lengths come from the file, so don't trust them to preallocate
+    fn len(&mut self) -> Result<usize, CacheError> {
+        let len = self.usize()?;
+        ensure!(len <= self.data.len(), Truncated);
+
+        Ok(len)
+    }
+}
+
+/// Whether every jump lands inside the program, on the jump that pairs with it
+///
+/// The vms index the program with jump distances without checking them, which is fine for ones
+/// `ir::transform` made, but not for ones read back from a file
+fn jumps_match(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .enumerate()
+        .all(|(idx, instr)| match *instr {
+            Instruction::JumpForwardsIfZero(jmp) => matches!(
+                idx.checked_add(jmp).and_then(|target| instructions.get(target)),
+                Some(Instruction::JumpBackwardsIfNotZero(back)) if jmp > 0 && *back == jmp
+            ),
+            Instruction::JumpBackwardsIfNotZero(jmp) => matches!(
+                idx.checked_sub(jmp).map(|target| &instructions[target]),
+                Some(Instruction::JumpForwardsIfZero(forwards)) if jmp > 0 && *forwards == jmp
+            ),
+            _ => true,
+        })
+}
+
+pub fn decode(data: &[u8]) -> Result<Entry, CacheError> {
+    ensure!(data.len() >= MAGIC.len() + 4 + 8, Truncated);
+    ensure!(&data[..MAGIC.len()] == MAGIC, BadMagic);
+
+    let (contents, checksum) = data.split_at(data.len() - 8);
+    let mut reader = Reader { data: contents };
+    reader.take(MAGIC.len())?;
+
+    let version = reader.u32()?;
+    ensure!(version == FORMAT_VERSION, UnsupportedVersion { version });
+    ensure!(hash(&[contents]).to_le_bytes() == checksum, Corrupted);
+
+    let count = reader.len()?;
+    let mut instructions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let instr = match reader.u8()? {
+            0 => Instruction::IncrementPointer(reader.i32()?),
+            1 => Instruction::IncrementByte(reader.i32()?),
+            2 => Instruction::IncrementPointerAndByte(reader.i32()?, reader.i32()?),
+            3 => Instruction::OutputByte,
+            4 => Instruction::ReadByte,
+            5 => Instruction::JumpForwardsIfZero(reader.usize()?),
+            6 => Instruction::JumpBackwardsIfNotZero(reader.usize()?),
+            tag => return UnknownInstruction { tag }.fail(),
+        };
+
+        instructions.push(instr);
+    }
+    ensure!(jumps_match(&instructions), Corrupted);
+
+    let assembly = match reader.u8()? {
+        0 => None,
+        1 => {
+            let len = reader.len()?;
+            let code = reader.take(len)?.to_vec();
+
+            let len = reader.len()?;
+            ensure!(len == instructions.len() + 1, Corrupted);
+            let source_map = (0..len)
+                .map(|_| reader.usize())
+                .collect::<Result<Vec<_>, _>>()?;
+            ensure!(
+                source_map.iter().all(|&offset| offset <= code.len()),
+                Corrupted
+            );
+
+            Some(Assembly { code, source_map })
+        }
+        _ => return Corrupted.fail(),
+    };
+
+    ensure!(reader.data.is_empty(), Corrupted);
+
+    Ok(Entry {
+        instructions,
+        assembly,
+    })
+}
+
+/// A directory of cache entries, one file per key
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Cache { dir }
+    }
+
+    /// $XDG_CACHE_HOME/bfr, falling back to ~/.cache/bfr
+    pub fn default_dir() -> Option<PathBuf> {
+        match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("bfr")),
+            _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("bfr")),
+        }
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.bfrc", key))
+    }
+
+    /// Returns the entry stored under `key`, if there's one
+    pub fn load(&self, key: u64) -> Result<Option<Entry>, CacheError> {
+        match fs::read(self.path(key)) {
+            Ok(data) => decode(&data).map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(Io),
+        }
+    }
+
+    pub fn store(&self, key: u64, entry: &Entry) -> Result<(), CacheError> {
+        fs::create_dir_all(&self.dir).context(Io)?;
+
+        // write somewhere else first, so other processes never see half of an entry
+        let temporary = self.dir.join(format!("{:016x}.{}.tmp", key, process::id()));
+        let written = fs::write(&temporary, encode(entry))
+            .and_then(|()| fs::rename(&temporary, self.path(key)));
+
+        if written.is_err() {
+            // it may not even exist, and there's a better error to report anyway
+            let _ = fs::remove_file(&temporary);
+        }
+
+        written.context(Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brainfuck, ir};
+
+    fn entry() -> Entry {
+        let instructions = ir::transform(&brainfuck::parse(b",[>++<-]>.".iter().copied())).unwrap();
+        let source_map = (0..=instructions.len()).map(|idx| idx * 3).collect();
+
+        Entry {
+            instructions,
+            assembly: Some(Assembly {
+                code: vec![0x90; 3 * 7],
+                source_map,
+            }),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let encoded = encode(&entry());
+        let decoded = decode(&encoded).unwrap();
+
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", entry()));
+        assert_eq!(encode(&decoded), encoded);
+    }
+
+    #[test]
+    fn rejects_truncated_entries() {
+        let encoded = encode(&entry());
+
+        assert!(matches!(decode(&encoded[..10]), Err(CacheError::Truncated)));
+        // dropping a byte off the end moves the checksum, so it doesn't match anymore
+        assert!(matches!(
+            decode(&encoded[..encoded.len() - 1]),
+            Err(CacheError::Corrupted)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut encoded = encode(&entry());
+        encoded[0] = b'X';
+
+        assert!(matches!(decode(&encoded), Err(CacheError::BadMagic)));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut encoded = encode(&entry());
+        encoded[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        assert!(matches!(
+            decode(&encoded),
+            Err(CacheError::UnsupportedVersion { version }) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_flipped_bits() {
+        let encoded = encode(&entry());
+
+        for idx in MAGIC.len() + 4..encoded.len() {
+            let mut flipped = encoded.clone();
+            flipped[idx] ^= 0x10;
+
+            assert!(matches!(decode(&flipped), Err(CacheError::Corrupted)));
+        }
+    }
+
+    #[test]
+    fn rejects_jumps_out_of_the_program() {
+        let jumps = [
+            vec![Instruction::JumpForwardsIfZero(5)],
+            vec![Instruction::JumpBackwardsIfNotZero(1)],
+            vec![
+                Instruction::JumpForwardsIfZero(2),
+                Instruction::OutputByte,
+                Instruction::JumpBackwardsIfNotZero(1),
+            ],
+        ];
+
+        for instructions in jumps.iter().cloned() {
+            let encoded = encode(&Entry {
+                instructions,
+                assembly: None,
+            });
+
+            assert!(matches!(decode(&encoded), Err(CacheError::Corrupted)));
+        }
+    }
+}
@@ -0,0 +1,86 @@
+/// Writes BFR IR back out as brainfuck, which shrinks programs for other interpreters
+///
+/// Comments are gone by the time we get the IR, and ir::transform has already merged runs of
+/// increments, so `+-` and `<>` pairs cancel out. On top of that, byte increments are reduced
+/// modulo 256 and loops that can't ever run are deleted
+use super::byte_increment;
+use crate::ir::Instruction;
+
+const LINE_WIDTH: usize = 80;
+
+fn repeat(source: &mut Vec<u8>, positive: u8, negative: u8, count: i32) {
+    let byte = if count < 0 { negative } else { positive };
+
+    for _ in 0..count.unsigned_abs() {
+        source.push(byte);
+    }
+}
+
+pub fn generate(instructions: &[Instruction]) -> String {
+    let mut source = Vec::new();
+
+    // a loop can't run if the current cell is known to be zero, which is the case for every cell
+    // until the program first changes one, and for the current cell right after a loop ends
+    let mut untouched = true;
+    let mut zero = true;
+    let mut idx = 0;
+
+    while idx < instructions.len() {
+        match instructions[idx] {
+            Instruction::IncrementPointer(inc) => {
+                repeat(&mut source, b'>', b'<', inc);
+                zero = untouched || (zero && inc == 0);
+            }
+            Instruction::IncrementByte(inc) => {
+                // 200 +'s are the same as 56 -'s
+                repeat(&mut source, b'+', b'-', byte_increment(inc));
+
+                if byte_increment(inc) != 0 {
+                    untouched = false;
+                    zero = false;
+                }
+            }
+            Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+                repeat(&mut source, b'>', b'<', ptr_inc);
+                repeat(&mut source, b'+', b'-', byte_increment(byte_inc));
+
+                zero = untouched || (zero && ptr_inc == 0);
+                if byte_increment(byte_inc) != 0 {
+                    untouched = false;
+                    zero = false;
+                }
+            }
+            Instruction::OutputByte => source.push(b'.'),
+            Instruction::ReadByte => {
+                source.push(b',');
+                untouched = false;
+                zero = false;
+            }
+            Instruction::JumpForwardsIfZero(jmp) => {
+                if zero {
+                    // skip the whole loop, including its JumpBackwardsIfNotZero
+                    idx += jmp + 1;
+                    continue;
+                }
+
+                source.push(b'[');
+                // we only know the cell isn't zero, and the body might've come from anywhere
+                zero = false;
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => {
+                source.push(b']');
+                zero = true;
+            }
+        }
+
+        idx += 1;
+    }
+
+    let mut text = String::new();
+    for line in source.chunks(LINE_WIDTH) {
+        text.push_str(std::str::from_utf8(line).unwrap());
+        text.push('\n');
+    }
+
+    text
+}
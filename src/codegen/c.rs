@@ -0,0 +1,92 @@
+/// Generates portable C99 from BFR IR
+///
+/// Loops become while loops and I/O goes through getchar and putchar, so any hosted C compiler
+/// will do
+use std::fmt::Write;
+
+use super::{byte_increment, indent, Eof, Options};
+use crate::ir::Instruction;
+
+const INDENT: &str = "    ";
+
+// the cell is unsigned, so wrapping around is well defined
+fn increment_byte(source: &mut String, inc: i32) {
+    let inc = byte_increment(inc);
+
+    if inc < 0 {
+        writeln!(source, "*p -= {};", -inc).unwrap();
+    } else if inc != 0 {
+        writeln!(source, "*p += {};", inc).unwrap();
+    } else {
+        // the indentation is already there
+        source.push_str(";\n");
+    }
+}
+
+fn increment_pointer(source: &mut String, inc: i32) {
+    if inc < 0 {
+        writeln!(source, "p -= {};", -i64::from(inc)).unwrap();
+    } else {
+        writeln!(source, "p += {};", inc).unwrap();
+    }
+}
+
+pub fn generate(instructions: &[Instruction], options: &Options) -> String {
+    let mut source = String::new();
+
+    writeln!(source, "/* Generated by bfr */").unwrap();
+    writeln!(source, "#include <stdio.h>").unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "#define TAPE_SIZE {}", options.tape_size).unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "static unsigned char tape[TAPE_SIZE];").unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "int main(void)").unwrap();
+    writeln!(source, "{{").unwrap();
+    writeln!(source, "    unsigned char *p = tape;").unwrap();
+    writeln!(source, "    int c;").unwrap();
+    writeln!(source).unwrap();
+
+    let mut depth = 1;
+
+    for instr in instructions {
+        if let Instruction::JumpBackwardsIfNotZero(_) = instr {
+            depth -= 1;
+        }
+
+        indent(&mut source, INDENT, depth);
+
+        match *instr {
+            Instruction::IncrementPointer(inc) => increment_pointer(&mut source, inc),
+            Instruction::IncrementByte(inc) => increment_byte(&mut source, inc),
+            Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+                increment_pointer(&mut source, ptr_inc);
+                indent(&mut source, INDENT, depth);
+                increment_byte(&mut source, byte_inc);
+            }
+            Instruction::OutputByte => source.push_str("putchar(*p);\n"),
+            Instruction::ReadByte => {
+                source.push_str("c = getchar();\n");
+                indent(&mut source, INDENT, depth);
+
+                match options.eof {
+                    Eof::Unchanged => source.push_str("if (c != EOF) *p = (unsigned char)c;\n"),
+                    Eof::Zero => source.push_str("*p = c == EOF ? 0 : (unsigned char)c;\n"),
+                    Eof::MinusOne => source.push_str("*p = c == EOF ? 255 : (unsigned char)c;\n"),
+                }
+            }
+            Instruction::JumpForwardsIfZero(_) => {
+                source.push_str("while (*p) {\n");
+                depth += 1;
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => source.push_str("}\n"),
+        }
+    }
+
+    writeln!(source).unwrap();
+    writeln!(source, "    (void)c;").unwrap();
+    writeln!(source, "    return 0;").unwrap();
+    writeln!(source, "}}").unwrap();
+
+    source
+}
@@ -0,0 +1,134 @@
+/// Generates a self-contained ES module from BFR IR
+///
+/// The module exports `run(input: Uint8Array): Uint8Array`, which runs the program on `input` and
+/// returns everything it wrote. Like ir::Vm, moving the data pointer off the tape is an error.
+/// Every loop becomes a function, as JavaScript engines only optimize reasonably sized functions
+use std::fmt::Write;
+
+use super::{byte_increment, indent, Eof, Options};
+use crate::ir::Instruction;
+
+const INDENT: &str = "  ";
+
+// assigning to a Uint8Array wraps around by itself
+fn increment_byte(source: &mut String, inc: i32) {
+    let inc = byte_increment(inc);
+
+    if inc < 0 {
+        writeln!(source, "tape[p] -= {};", -inc).unwrap();
+    } else {
+        writeln!(source, "tape[p] += {};", inc).unwrap();
+    }
+}
+
+fn increment_pointer(source: &mut String, depth: usize, inc: i32) {
+    if inc < 0 {
+        writeln!(source, "p -= {};", -i64::from(inc)).unwrap();
+        indent(source, INDENT, depth);
+        writeln!(source, "if (p < 0) outOfBounds();").unwrap();
+    } else {
+        writeln!(source, "p += {};", inc).unwrap();
+        indent(source, INDENT, depth);
+        writeln!(source, "if (p >= TAPE_SIZE) outOfBounds();").unwrap();
+    }
+}
+
+const PRELUDE: &str = "
+function outOfBounds() {
+  throw new RangeError(\"data pointer out of bounds\");
+}
+
+/**
+ * Runs the program and returns everything it wrote
+ * @param {Uint8Array} input bytes the program reads, after which reads hit the end of input
+ * @returns {Uint8Array}
+ */
+export function run(input) {
+  const tape = new Uint8Array(TAPE_SIZE);
+  let p = 0;
+  let read = 0;
+  let output = new Uint8Array(4096);
+  let written = 0;
+
+  function write(byte) {
+    if (written === output.length) {
+      const grown = new Uint8Array(output.length * 2);
+      grown.set(output);
+      output = grown;
+    }
+
+    output[written++] = byte;
+  }
+
+";
+
+pub fn generate(instructions: &[Instruction], options: &Options) -> String {
+    // V8 won't optimize a function as big as a whole program, so every loop gets its own function,
+    // which takes and returns the data pointer. The last block is the one being generated
+    let mut blocks = vec![String::new()];
+    let mut loops = Vec::new();
+    let mut functions = String::new();
+
+    for (idx, instr) in instructions.iter().enumerate() {
+        let depth = if loops.is_empty() { 1 } else { 3 };
+        let source = blocks.last_mut().unwrap();
+
+        if let Instruction::JumpBackwardsIfNotZero(_) = instr {
+            let body = blocks.pop().unwrap();
+            let start = loops.pop().expect("the IR has balanced loops");
+
+            writeln!(functions, "  function loop{}(p) {{", start).unwrap();
+            writeln!(functions, "    while (tape[p] !== 0) {{").unwrap();
+            functions.push_str(&body);
+            writeln!(functions, "    }}").unwrap();
+            writeln!(functions, "    return p;").unwrap();
+            writeln!(functions, "  }}").unwrap();
+            writeln!(functions).unwrap();
+
+            continue;
+        }
+
+        indent(source, INDENT, depth);
+
+        match *instr {
+            Instruction::IncrementPointer(inc) => increment_pointer(source, depth, inc),
+            Instruction::IncrementByte(inc) => increment_byte(source, inc),
+            Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+                increment_pointer(source, depth, ptr_inc);
+                indent(source, INDENT, depth);
+                increment_byte(source, byte_inc);
+            }
+            Instruction::OutputByte => source.push_str("write(tape[p]);\n"),
+            Instruction::ReadByte => match options.eof {
+                Eof::Unchanged => {
+                    source.push_str("if (read < input.length) tape[p] = input[read++];\n")
+                }
+                Eof::Zero => {
+                    source.push_str("tape[p] = read < input.length ? input[read++] : 0;\n")
+                }
+                Eof::MinusOne => {
+                    source.push_str("tape[p] = read < input.length ? input[read++] : 255;\n")
+                }
+            },
+            Instruction::JumpForwardsIfZero(_) => {
+                writeln!(source, "p = loop{}(p);", idx).unwrap();
+                loops.push(idx);
+                blocks.push(String::new());
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => unreachable!(),
+        }
+    }
+
+    let mut source = String::new();
+
+    writeln!(source, "// Generated by bfr").unwrap();
+    writeln!(source, "const TAPE_SIZE = {};", options.tape_size).unwrap();
+    source.push_str(PRELUDE);
+    source.push_str(&functions);
+    source.push_str(&blocks[0]);
+    writeln!(source).unwrap();
+    writeln!(source, "  return output.slice(0, written);").unwrap();
+    writeln!(source, "}}").unwrap();
+
+    source
+}
@@ -0,0 +1,168 @@
+/// Generates textual LLVM IR from BFR IR
+///
+/// The tape is a global array accessed through GEPs, the data pointer is an index kept in an
+/// alloca (which `opt`'s mem2reg turns into registers) and every loop gets a condition, a body and
+/// an end block. I/O goes through libc's getchar and putchar. The output uses opaque pointers, so
+/// it needs LLVM 15 or later
+use std::fmt::Write;
+
+use super::{Eof, Options};
+use crate::ir::Instruction;
+
+struct Function {
+    body: String,
+    temporaries: usize,
+    tape_type: String,
+}
+
+impl Function {
+    fn temporary(&mut self) -> String {
+        self.temporaries += 1;
+        format!("%t{}", self.temporaries)
+    }
+
+    fn line(&mut self, line: &str) {
+        writeln!(self.body, "  {}", line).unwrap();
+    }
+
+    fn label(&mut self, label: &str) {
+        writeln!(self.body, "{}:", label).unwrap();
+    }
+
+    /// Returns a pointer to the current cell
+    fn cell(&mut self) -> String {
+        let index = self.temporary();
+        let cell = self.temporary();
+        self.line(&format!("{} = load i64, ptr %dp", index));
+        let gep = format!(
+            "{} = getelementptr inbounds {}, ptr @tape, i64 0, i64 {}",
+            cell, self.tape_type, index
+        );
+        self.line(&gep);
+
+        cell
+    }
+
+    fn increment_pointer(&mut self, inc: i32) {
+        let old = self.temporary();
+        let new = self.temporary();
+        self.line(&format!("{} = load i64, ptr %dp", old));
+        self.line(&format!("{} = add i64 {}, {}", new, old, inc));
+        self.line(&format!("store i64 {}, ptr %dp", new));
+    }
+
+    // i8 arithmetic wraps, so the increment only needs its low byte
+    fn increment_byte(&mut self, inc: i32) {
+        let cell = self.cell();
+        let old = self.temporary();
+        let new = self.temporary();
+        self.line(&format!("{} = load i8, ptr {}", old, cell));
+        self.line(&format!("{} = add i8 {}, {}", new, old, inc as i8));
+        self.line(&format!("store i8 {}, ptr {}", new, cell));
+    }
+
+    /// Branches to `then` if the current cell isn't zero, or to `otherwise` if it is
+    fn branch_on_cell(&mut self, then: &str, otherwise: &str) {
+        let cell = self.cell();
+        let value = self.temporary();
+        let condition = self.temporary();
+        self.line(&format!("{} = load i8, ptr {}", value, cell));
+        self.line(&format!("{} = icmp ne i8 {}, 0", condition, value));
+        self.line(&format!(
+            "br i1 {}, label %{}, label %{}",
+            condition, then, otherwise
+        ));
+    }
+}
+
+pub fn generate(instructions: &[Instruction], options: &Options) -> String {
+    let mut function = Function {
+        body: String::new(),
+        temporaries: 0,
+        tape_type: format!("[{} x i8]", options.tape_size),
+    };
+    let mut loops = Vec::new();
+
+    function.label("entry");
+    function.line("%dp = alloca i64");
+    function.line("store i64 0, ptr %dp");
+
+    for (idx, instr) in instructions.iter().enumerate() {
+        match *instr {
+            Instruction::IncrementPointer(inc) => function.increment_pointer(inc),
+            Instruction::IncrementByte(inc) => function.increment_byte(inc),
+            Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+                function.increment_pointer(ptr_inc);
+                function.increment_byte(byte_inc);
+            }
+            Instruction::OutputByte => {
+                let cell = function.cell();
+                let value = function.temporary();
+                let extended = function.temporary();
+                let result = function.temporary();
+                function.line(&format!("{} = load i8, ptr {}", value, cell));
+                function.line(&format!("{} = zext i8 {} to i32", extended, value));
+                function.line(&format!("{} = call i32 @putchar(i32 {})", result, extended));
+            }
+            Instruction::ReadByte => {
+                let read = function.temporary();
+                let eof = function.temporary();
+                let byte = function.temporary();
+                function.line(&format!("{} = call i32 @getchar()", read));
+                function.line(&format!("{} = icmp slt i32 {}, 0", eof, read));
+                function.line(&format!("{} = trunc i32 {} to i8", byte, read));
+
+                let cell = function.cell();
+                let on_eof = match options.eof {
+                    Eof::Unchanged => {
+                        let old = function.temporary();
+                        function.line(&format!("{} = load i8, ptr {}", old, cell));
+                        old
+                    }
+                    Eof::Zero => String::from("0"),
+                    Eof::MinusOne => String::from("-1"),
+                };
+
+                let new = function.temporary();
+                function.line(&format!(
+                    "{} = select i1 {}, i8 {}, i8 {}",
+                    new, eof, on_eof, byte
+                ));
+                function.line(&format!("store i8 {}, ptr {}", new, cell));
+            }
+            Instruction::JumpForwardsIfZero(_) => {
+                let condition = format!("loop{}", idx);
+                function.line(&format!("br label %{}", condition));
+                function.label(&condition);
+                function.branch_on_cell(&format!("loop{}.body", idx), &format!("loop{}.end", idx));
+                function.label(&format!("loop{}.body", idx));
+                loops.push(idx);
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => {
+                let start = loops.pop().expect("the IR has balanced loops");
+                function.line(&format!("br label %loop{}", start));
+                function.label(&format!("loop{}.end", start));
+            }
+        }
+    }
+
+    function.line("ret i32 0");
+
+    let mut source = String::new();
+    writeln!(source, "; Generated by bfr").unwrap();
+    writeln!(
+        source,
+        "@tape = internal global {} zeroinitializer",
+        function.tape_type
+    )
+    .unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "declare i32 @getchar()").unwrap();
+    writeln!(source, "declare i32 @putchar(i32)").unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "define i32 @main() {{").unwrap();
+    source.push_str(&function.body);
+    writeln!(source, "}}").unwrap();
+
+    source
+}
@@ -0,0 +1,71 @@
+#![forbid(unsafe_code)]
+
+/// Code generators that turn BFR IR into source code for other compilers
+use std::str::FromStr;
+
+pub mod bf;
+pub mod c;
+pub mod js;
+pub mod llvm;
+pub mod rust;
+pub mod wasm;
+
+/// Indents a line of generated code `depth` levels deep, each one `unit` wide
+pub fn indent(source: &mut String, unit: &str, depth: usize) {
+    for _ in 0..depth {
+        source.push_str(unit);
+    }
+}
+
+/// Reduces a byte increment modulo 256, folding ones above 128 into a decrement, so 200 is -56
+pub fn byte_increment(inc: i32) -> i32 {
+    let inc = inc.rem_euclid(256);
+
+    if inc > 128 {
+        inc - 256
+    } else {
+        inc
+    }
+}
+
+/// What a read does to the current cell once the input runs out
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Eof {
+    /// Leaves the cell as it was, like the compiled executables do
+    Unchanged,
+    Zero,
+    /// Sets the cell to 255
+    MinusOne,
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(Eof::Unchanged),
+            "zero" => Ok(Eof::Zero),
+            "minus-one" => Ok(Eof::MinusOne),
+            _ => Err(format!(
+                "unknown EOF behaviour: {} (expected unchanged, zero or minus-one)",
+                s
+            )),
+        }
+    }
+}
+
+/// Semantics the generated code should have, which the other compiler can't know about
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+    pub tape_size: usize,
+    pub eof: Eof,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            tape_size: 30000,
+            eof: Eof::Unchanged,
+        }
+    }
+}
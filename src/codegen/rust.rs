@@ -0,0 +1,149 @@
+/// Generates a Rust module from BFR IR
+///
+/// The module has a single `pub fn run(input: &mut impl Read, output: &mut impl Write) ->
+/// io::Result<()>` and depends on nothing but std, so it can be vendored into any crate
+use std::fmt::Write;
+
+use super::{byte_increment, indent, Eof, Options};
+use crate::ir::Instruction;
+
+const INDENT: &str = "    ";
+
+fn increment_byte(source: &mut String, inc: i32) {
+    let inc = byte_increment(inc);
+
+    if inc < 0 {
+        writeln!(source, "tape[p] = tape[p].wrapping_sub({});", -inc).unwrap();
+    } else {
+        writeln!(source, "tape[p] = tape[p].wrapping_add({});", inc).unwrap();
+    }
+}
+
+fn increment_pointer(source: &mut String, inc: i32) {
+    if inc < 0 {
+        writeln!(source, "p -= {};", -i64::from(inc)).unwrap();
+    } else {
+        writeln!(source, "p += {};", inc).unwrap();
+    }
+}
+
+const READ_BYTE: &str = "
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+";
+
+pub fn generate(instructions: &[Instruction], options: &Options) -> String {
+    // unused variables and needless muts would make the generated code warn
+    let reads = instructions
+        .iter()
+        .any(|instr| matches!(instr, Instruction::ReadByte));
+    let moves = instructions.iter().any(|instr| {
+        matches!(
+            instr,
+            Instruction::IncrementPointer(_) | Instruction::IncrementPointerAndByte(..)
+        )
+    });
+    // a program that only moves the pointer around can't do anything you'd notice
+    let touches_tape = instructions
+        .iter()
+        .any(|instr| !matches!(instr, Instruction::IncrementPointer(_)));
+    let writes = reads
+        || instructions.iter().any(|instr| {
+            matches!(
+                instr,
+                Instruction::IncrementByte(_) | Instruction::IncrementPointerAndByte(..)
+            )
+        });
+
+    let mut source = String::new();
+
+    writeln!(source, "// Generated by bfr, do not edit").unwrap();
+    writeln!(source, "use std::io;").unwrap();
+    writeln!(source, "use std::io::{{Read, Write}};").unwrap();
+
+    if touches_tape {
+        writeln!(source).unwrap();
+        writeln!(source, "const TAPE_SIZE: usize = {};", options.tape_size).unwrap();
+    }
+
+    if reads {
+        source.push_str(READ_BYTE);
+    }
+
+    writeln!(source).unwrap();
+    writeln!(
+        source,
+        "/// Runs the program, panicking if it moves off either end of the tape"
+    )
+    .unwrap();
+    writeln!(
+        source,
+        "pub fn run(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {{"
+    )
+    .unwrap();
+
+    if !reads {
+        writeln!(source, "    let _ = input;").unwrap();
+    }
+
+    if touches_tape {
+        let tape_mut = if writes { "mut " } else { "" };
+        let p_mut = if moves { "mut " } else { "" };
+        writeln!(source, "    let {}tape = vec![0u8; TAPE_SIZE];", tape_mut).unwrap();
+        writeln!(source, "    let {}p = 0usize;", p_mut).unwrap();
+        writeln!(source).unwrap();
+    }
+
+    let mut depth = 1;
+
+    for instr in instructions.iter().filter(|_| touches_tape) {
+        if let Instruction::JumpBackwardsIfNotZero(_) = instr {
+            depth -= 1;
+        }
+
+        indent(&mut source, INDENT, depth);
+
+        match *instr {
+            Instruction::IncrementPointer(inc) => increment_pointer(&mut source, inc),
+            Instruction::IncrementByte(inc) => increment_byte(&mut source, inc),
+            Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+                increment_pointer(&mut source, ptr_inc);
+                indent(&mut source, INDENT, depth);
+                increment_byte(&mut source, byte_inc);
+            }
+            Instruction::OutputByte => source.push_str("output.write_all(&[tape[p]])?;\n"),
+            Instruction::ReadByte => match options.eof {
+                Eof::Unchanged => {
+                    source.push_str("if let Some(byte) = read_byte(input)? {\n");
+                    indent(&mut source, INDENT, depth + 1);
+                    source.push_str("tape[p] = byte;\n");
+                    indent(&mut source, INDENT, depth);
+                    source.push_str("}\n");
+                }
+                Eof::Zero => source.push_str("tape[p] = read_byte(input)?.unwrap_or(0);\n"),
+                Eof::MinusOne => source.push_str("tape[p] = read_byte(input)?.unwrap_or(255);\n"),
+            },
+            Instruction::JumpForwardsIfZero(_) => {
+                source.push_str("while tape[p] != 0 {\n");
+                depth += 1;
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => source.push_str("}\n"),
+        }
+    }
+
+    writeln!(source).unwrap();
+    writeln!(source, "    output.flush()").unwrap();
+    writeln!(source, "}}").unwrap();
+
+    source
+}
@@ -0,0 +1,411 @@
+/// A tiny WebAssembly interpreter that understands exactly what the backend produces
+///
+/// It's here so the backend can be checked without a browser or a wasm runtime around. Modules are
+/// not validated beyond what's needed to run them, anything unexpected is reported as malformed
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::io;
+use std::io::{Read, Write};
+
+use super::{Op, MAGIC, PAGE_SIZE, VERSION};
+
+#[derive(Snafu, Debug)]
+pub enum WasmError {
+    #[snafu(display("Malformed or unsupported module"))]
+    Malformed,
+    #[snafu(display("Out of bounds memory access at {}", address))]
+    OutOfBounds { address: i32 },
+    #[snafu(display("Failed to write byte to output"))]
+    FailedToWrite { source: io::Error },
+    #[snafu(display("Failed to read byte from input"))]
+    FailedToRead { source: io::Error },
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], WasmError> {
+        ensure!(len <= self.data.len(), Malformed);
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, WasmError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uleb(&mut self) -> Result<u32, WasmError> {
+        let mut value = 0u32;
+
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            value |= u32::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Malformed.fail()
+    }
+
+    fn sleb(&mut self) -> Result<i32, WasmError> {
+        let mut value = 0i64;
+        let mut shift = 0;
+
+        loop {
+            ensure!(shift < 35, Malformed);
+
+            let byte = self.u8()?;
+            value |= i64::from(byte & 0x7f) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+
+                return Ok(value as i32);
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a [u8], WasmError> {
+        let len = self.uleb()? as usize;
+        self.take(len)
+    }
+
+    fn op(&mut self) -> Result<Op, WasmError> {
+        let op = match self.u8()? {
+            0x02 | 0x03 if self.u8()? != 0x40 => return Malformed.fail(),
+            0x02 => Op::Block,
+            0x03 => Op::Loop,
+            0x0b => Op::End,
+            0x0d => Op::BrIf(self.uleb()?),
+            0x10 => Op::Call(self.uleb()?),
+            0x1b => Op::Select,
+            0x20 => Op::LocalGet(self.uleb()?),
+            0x21 => Op::LocalSet(self.uleb()?),
+            opcode @ 0x2d | opcode @ 0x3a => {
+                // alignment is only a hint, but a nonzero offset would change the address
+                self.uleb()?;
+                ensure!(self.uleb()? == 0, Malformed);
+
+                if opcode == 0x2d {
+                    Op::I32Load8U
+                } else {
+                    Op::I32Store8
+                }
+            }
+            0x41 => Op::I32Const(self.sleb()?),
+            0x45 => Op::I32Eqz,
+            0x4e => Op::I32GeS,
+            0x6a => Op::I32Add,
+            _ => return Malformed.fail(),
+        };
+
+        Ok(op)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Host {
+    Read,
+    Write,
+}
+
+/// A module ready to run
+pub struct Instance {
+    memory: Vec<u8>,
+    imports: Vec<Host>,
+    locals: usize,
+    body: Vec<Op>,
+    /// Where the End of each Block or Loop is
+    ends: Vec<usize>,
+}
+
+impl Instance {
+    pub fn new(wasm: &[u8]) -> Result<Self, WasmError> {
+        let mut reader = Reader { data: wasm };
+        ensure!(reader.take(4)? == MAGIC, Malformed);
+        ensure!(reader.take(4)? == VERSION.to_le_bytes(), Malformed);
+
+        let mut imports = Vec::new();
+        let mut memory = None;
+        let mut run = None;
+        let mut code = None;
+
+        while !reader.data.is_empty() {
+            let id = reader.u8()?;
+            let len = reader.uleb()? as usize;
+            let mut section = Reader {
+                data: reader.take(len)?,
+            };
+
+            match id {
+                2 => {
+                    for _ in 0..section.uleb()? {
+                        ensure!(section.name()? == b"env", Malformed);
+                        let host = match section.name()? {
+                            b"read" => Host::Read,
+                            b"write" => Host::Write,
+                            _ => return Malformed.fail(),
+                        };
+                        ensure!(section.u8()? == 0x00, Malformed);
+                        section.uleb()?;
+
+                        imports.push(host);
+                    }
+                }
+                5 => {
+                    ensure!(section.uleb()? == 1, Malformed);
+                    section.u8()?;
+                    memory = Some(section.uleb()? as usize * PAGE_SIZE);
+                }
+                7 => {
+                    for _ in 0..section.uleb()? {
+                        let name = section.name()?;
+                        let kind = section.u8()?;
+                        let idx = section.uleb()?;
+
+                        if name == b"run" && kind == 0x00 {
+                            run = Some(idx as usize);
+                        }
+                    }
+                }
+                10 => {
+                    ensure!(section.uleb()? == 1, Malformed);
+                    let len = section.uleb()? as usize;
+                    code = Some(section.take(len)?);
+                }
+                // the other sections only describe what's in the ones above
+                _ => (),
+            }
+        }
+
+        // run has to be the only function the module defines
+        ensure!(run == Some(imports.len()), Malformed);
+
+        let mut code = Reader {
+            data: code.context(Malformed)?,
+        };
+        let mut locals = 0;
+        for _ in 0..code.uleb()? {
+            locals += code.uleb()? as usize;
+            ensure!(code.u8()? == super::I32, Malformed);
+        }
+
+        let mut body = Vec::new();
+        while !code.data.is_empty() {
+            body.push(code.op()?);
+        }
+
+        let mut ends = vec![0; body.len()];
+        let mut open = Vec::new();
+        for (idx, op) in body.iter().enumerate() {
+            match op {
+                Op::Block | Op::Loop => open.push(idx),
+                // the last End belongs to the function
+                Op::End => {
+                    if let Some(start) = open.pop() {
+                        ends[start] = idx;
+                    }
+                }
+                _ => (),
+            }
+        }
+        ensure!(open.is_empty() && body.last() == Some(&Op::End), Malformed);
+
+        Ok(Instance {
+            memory: vec![0; memory.context(Malformed)?],
+            imports,
+            locals,
+            body,
+            ends,
+        })
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Calls the exported run function
+    pub fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), WasmError> {
+        let mut locals = vec![0i32; self.locals];
+        let mut stack = Vec::new();
+        // where branching to each enclosing Block or Loop goes, and whether it's a Loop
+        let mut labels: Vec<(usize, bool)> = Vec::new();
+        let mut pc = 0;
+
+        macro_rules! pop {
+            () => {
+                stack.pop().context(Malformed)?
+            };
+        }
+
+        while pc < self.body.len() {
+            match self.body[pc] {
+                Op::Block => labels.push((self.ends[pc] + 1, false)),
+                Op::Loop => labels.push((pc + 1, true)),
+                Op::End => {
+                    if labels.pop().is_none() {
+                        return Ok(());
+                    }
+                }
+                Op::BrIf(depth) => {
+                    if pop!() != 0 {
+                        let idx = labels
+                            .len()
+                            .checked_sub(depth as usize + 1)
+                            .context(Malformed)?;
+                        let (target, is_loop) = labels[idx];
+
+                        // branching to a Loop stays inside of it
+                        labels.truncate(if is_loop { idx + 1 } else { idx });
+                        pc = target;
+                        continue;
+                    }
+                }
+                Op::Call(function) => match self.imports.get(function as usize) {
+                    Some(Host::Read) => {
+                        let mut byte = [0];
+                        let read = input.read(&mut byte).context(FailedToRead)?;
+                        stack.push(if read == 0 { -1 } else { i32::from(byte[0]) });
+                    }
+                    Some(Host::Write) => {
+                        let byte = pop!() as u8;
+                        output.write_all(&[byte]).context(FailedToWrite)?;
+                    }
+                    None => return Malformed.fail(),
+                },
+                Op::Select => {
+                    let condition = pop!();
+                    let second = pop!();
+                    let first = pop!();
+                    stack.push(if condition != 0 { first } else { second });
+                }
+                Op::LocalGet(idx) => stack.push(*locals.get(idx as usize).context(Malformed)?),
+                Op::LocalSet(idx) => {
+                    let value = pop!();
+                    *locals.get_mut(idx as usize).context(Malformed)? = value;
+                }
+                Op::I32Load8U => {
+                    let address = pop!();
+                    let byte = self
+                        .memory
+                        .get(address as u32 as usize)
+                        .context(OutOfBounds { address })?;
+                    stack.push(i32::from(*byte));
+                }
+                Op::I32Store8 => {
+                    let value = pop!();
+                    let address = pop!();
+                    let byte = self
+                        .memory
+                        .get_mut(address as u32 as usize)
+                        .context(OutOfBounds { address })?;
+                    *byte = value as u8;
+                }
+                Op::I32Const(value) => stack.push(value),
+                Op::I32Eqz => {
+                    let value = pop!();
+                    stack.push((value == 0) as i32);
+                }
+                Op::I32GeS => {
+                    let right = pop!();
+                    let left = pop!();
+                    stack.push((left >= right) as i32);
+                }
+                Op::I32Add => {
+                    let right = pop!();
+                    let left = pop!();
+                    stack.push(left.wrapping_add(right));
+                }
+            }
+
+            pc += 1;
+        }
+
+        Malformed.fail()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{wasm, Eof, Options};
+    use crate::{brainfuck, ir};
+
+    fn transform(source: &[u8]) -> Vec<ir::Instruction> {
+        ir::transform(&brainfuck::parse(source.iter().copied())).unwrap()
+    }
+
+    fn run_wasm(source: &[u8], input: &[u8], options: &Options) -> Vec<u8> {
+        let module = wasm::lower(&transform(source), options).encode();
+        let mut output = Vec::new();
+
+        Instance::new(&module)
+            .unwrap()
+            .run(&mut &input[..], &mut output)
+            .unwrap();
+
+        output
+    }
+
+    fn run_ir(source: &[u8], input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        ir::Vm::new(transform(source))
+            .vm_loop(&mut &input[..], &mut output)
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    fn matches_the_ir_vm_on_test_b() {
+        let source = include_bytes!("../../../programs/test.b");
+
+        assert_eq!(
+            run_wasm(source, b"", &Options::default()),
+            run_ir(source, b"")
+        );
+    }
+
+    #[test]
+    fn matches_the_ir_vm_with_loops_and_reads() {
+        // adds up the first two bytes, then echoes the rest with each byte moved to the next cell
+        let source = b",>,[<+>-]<.[-],[[>+<-]>.[-]<,]";
+        let input = b"\x20\x21rest of the input";
+
+        let expected = run_ir(source, input);
+        assert_eq!(expected[0], 0x41);
+        assert_eq!(run_wasm(source, input, &Options::default()), expected);
+    }
+
+    #[test]
+    fn reads_at_the_end_of_input_follow_eof() {
+        let with = |eof| {
+            let options = Options {
+                eof,
+                ..Options::default()
+            };
+            run_wasm(b"+,.", b"", &options)
+        };
+
+        assert_eq!(with(Eof::Unchanged), [1]);
+        assert_eq!(with(Eof::Zero), [0]);
+        assert_eq!(with(Eof::MinusOne), [255]);
+    }
+
+    #[test]
+    fn wat_matches_golden_output() {
+        let module = wasm::lower(&transform(b"++[>+<-]>.,."), &Options::default());
+
+        assert_eq!(module.to_wat(), include_str!("testdata/small.wat"));
+    }
+}
@@ -0,0 +1,352 @@
+/// Lowers BFR IR to a WebAssembly module, either as a binary .wasm or as text .wat
+///
+/// The module imports `env.read: () -> i32`, which returns the next byte of input or -1 once it
+/// runs out, and `env.write: (i32) -> ()`, which writes a byte. It exports its memory, which holds
+/// the tape starting at address 0, and `run: () -> ()`, which runs the whole program
+use std::fmt;
+use std::fmt::Write;
+
+use super::{Eof, Options};
+use crate::ir::Instruction;
+
+pub mod interp;
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: u32 = 1;
+const PAGE_SIZE: usize = 65536;
+
+// value types
+const I32: u8 = 0x7f;
+
+// imports come before the functions a module defines, so run is function 2
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const RUN: u32 = 2;
+
+// types in the order encode writes them, one for each function
+const READ_TYPE: u32 = 0;
+const WRITE_TYPE: u32 = 1;
+const RUN_TYPE: u32 = 2;
+
+// locals of run
+const POINTER: u32 = 0;
+const CHARACTER: u32 = 1;
+const LOCALS: u32 = 2;
+
+/// The few WebAssembly instructions the backend uses
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    Block,
+    Loop,
+    End,
+    BrIf(u32),
+    Call(u32),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    I32Load8U,
+    I32Store8,
+    I32Const(i32),
+    I32Eqz,
+    I32GeS,
+    I32Add,
+}
+
+fn uleb(buffer: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buffer.push(byte);
+            return;
+        }
+
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn sleb(buffer: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        // done once the rest is just copies of the sign bit, which is bit 6 of the last byte
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buffer.push(byte);
+            return;
+        }
+
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn name(buffer: &mut Vec<u8>, name: &str) {
+    uleb(buffer, name.len() as u32);
+    buffer.extend_from_slice(name.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    uleb(module, contents.len() as u32);
+    module.extend_from_slice(contents);
+}
+
+impl Op {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            // 0x40 is the empty block type
+            Op::Block => buffer.extend_from_slice(&[0x02, 0x40]),
+            Op::Loop => buffer.extend_from_slice(&[0x03, 0x40]),
+            Op::End => buffer.push(0x0b),
+            Op::BrIf(depth) => {
+                buffer.push(0x0d);
+                uleb(buffer, depth);
+            }
+            Op::Call(function) => {
+                buffer.push(0x10);
+                uleb(buffer, function);
+            }
+            Op::Select => buffer.push(0x1b),
+            Op::LocalGet(local) => {
+                buffer.push(0x20);
+                uleb(buffer, local);
+            }
+            Op::LocalSet(local) => {
+                buffer.push(0x21);
+                uleb(buffer, local);
+            }
+            // byte accesses with no alignment and no offset
+            Op::I32Load8U => buffer.extend_from_slice(&[0x2d, 0x00, 0x00]),
+            Op::I32Store8 => buffer.extend_from_slice(&[0x3a, 0x00, 0x00]),
+            Op::I32Const(value) => {
+                buffer.push(0x41);
+                sleb(buffer, value);
+            }
+            Op::I32Eqz => buffer.push(0x45),
+            Op::I32GeS => buffer.push(0x4e),
+            Op::I32Add => buffer.push(0x6a),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let local = |local| if local == POINTER { "$p" } else { "$c" };
+
+        match *self {
+            Op::Block => write!(f, "block"),
+            Op::Loop => write!(f, "loop"),
+            Op::End => write!(f, "end"),
+            Op::BrIf(depth) => write!(f, "br_if {}", depth),
+            Op::Call(READ) => write!(f, "call $read"),
+            Op::Call(WRITE) => write!(f, "call $write"),
+            Op::Call(function) => write!(f, "call {}", function),
+            Op::Select => write!(f, "select"),
+            Op::LocalGet(idx) => write!(f, "local.get {}", local(idx)),
+            Op::LocalSet(idx) => write!(f, "local.set {}", local(idx)),
+            Op::I32Load8U => write!(f, "i32.load8_u"),
+            Op::I32Store8 => write!(f, "i32.store8"),
+            Op::I32Const(value) => write!(f, "i32.const {}", value),
+            Op::I32Eqz => write!(f, "i32.eqz"),
+            Op::I32GeS => write!(f, "i32.ge_s"),
+            Op::I32Add => write!(f, "i32.add"),
+        }
+    }
+}
+
+/// A module running a single program
+pub struct Module {
+    pub memory_pages: u32,
+    /// Body of the run function, including its final End
+    pub body: Vec<Op>,
+}
+
+fn increment_pointer(body: &mut Vec<Op>, inc: i32) {
+    body.extend_from_slice(&[
+        Op::LocalGet(POINTER),
+        Op::I32Const(inc),
+        Op::I32Add,
+        Op::LocalSet(POINTER),
+    ]);
+}
+
+// store8 only keeps the low byte, so the increment wraps around by itself
+fn increment_byte(body: &mut Vec<Op>, inc: i32) {
+    body.extend_from_slice(&[
+        Op::LocalGet(POINTER),
+        Op::LocalGet(POINTER),
+        Op::I32Load8U,
+        Op::I32Const(inc),
+        Op::I32Add,
+        Op::I32Store8,
+    ]);
+}
+
+pub fn lower(instructions: &[Instruction], options: &Options) -> Module {
+    let mut body = Vec::new();
+
+    for instr in instructions {
+        match *instr {
+            Instruction::IncrementPointer(inc) => increment_pointer(&mut body, inc),
+            Instruction::IncrementByte(inc) => increment_byte(&mut body, inc),
+            Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+                increment_pointer(&mut body, ptr_inc);
+                increment_byte(&mut body, byte_inc);
+            }
+            Instruction::OutputByte => {
+                body.extend_from_slice(&[Op::LocalGet(POINTER), Op::I32Load8U, Op::Call(WRITE)])
+            }
+            Instruction::ReadByte => {
+                body.extend_from_slice(&[
+                    Op::Call(READ),
+                    Op::LocalSet(CHARACTER),
+                    Op::LocalGet(POINTER),
+                    Op::LocalGet(CHARACTER),
+                ]);
+
+                // select picks the read byte unless the input ran out
+                match options.eof {
+                    Eof::Unchanged => {
+                        body.extend_from_slice(&[Op::LocalGet(POINTER), Op::I32Load8U])
+                    }
+                    Eof::Zero => body.push(Op::I32Const(0)),
+                    // -1 is 255 once stored
+                    Eof::MinusOne => body.push(Op::I32Const(-1)),
+                }
+
+                body.extend_from_slice(&[
+                    Op::LocalGet(CHARACTER),
+                    Op::I32Const(0),
+                    Op::I32GeS,
+                    Op::Select,
+                    Op::I32Store8,
+                ]);
+            }
+            // skip the loop entirely if the cell is zero, otherwise loop until it is
+            Instruction::JumpForwardsIfZero(_) => body.extend_from_slice(&[
+                Op::Block,
+                Op::LocalGet(POINTER),
+                Op::I32Load8U,
+                Op::I32Eqz,
+                Op::BrIf(0),
+                Op::Loop,
+            ]),
+            Instruction::JumpBackwardsIfNotZero(_) => body.extend_from_slice(&[
+                Op::LocalGet(POINTER),
+                Op::I32Load8U,
+                Op::BrIf(0),
+                Op::End,
+                Op::End,
+            ]),
+        }
+    }
+
+    body.push(Op::End);
+
+    Module {
+        memory_pages: options.tape_size.div_ceil(PAGE_SIZE) as u32,
+        body,
+    }
+}
+
+impl Module {
+    /// Encodes the module in the binary format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut module = Vec::new();
+        module.extend_from_slice(MAGIC);
+        module.extend_from_slice(&VERSION.to_le_bytes());
+
+        // read: () -> i32, write: (i32) -> (), run: () -> ()
+        let mut types = Vec::new();
+        uleb(&mut types, 3);
+        types.extend_from_slice(&[0x60, 0, 1, I32]);
+        types.extend_from_slice(&[0x60, 1, I32, 0]);
+        types.extend_from_slice(&[0x60, 0, 0]);
+        section(&mut module, 1, &types);
+
+        let mut imports = Vec::new();
+        uleb(&mut imports, 2);
+        for &(function, ty) in &[("read", READ_TYPE), ("write", WRITE_TYPE)] {
+            name(&mut imports, "env");
+            name(&mut imports, function);
+            imports.push(0x00);
+            uleb(&mut imports, ty);
+        }
+        section(&mut module, 2, &imports);
+
+        let mut functions = Vec::new();
+        uleb(&mut functions, 1);
+        uleb(&mut functions, RUN_TYPE);
+        section(&mut module, 3, &functions);
+
+        let mut memories = Vec::new();
+        uleb(&mut memories, 1);
+        memories.push(0x00);
+        uleb(&mut memories, self.memory_pages);
+        section(&mut module, 5, &memories);
+
+        let mut exports = Vec::new();
+        uleb(&mut exports, 2);
+        name(&mut exports, "memory");
+        exports.push(0x02);
+        uleb(&mut exports, 0);
+        name(&mut exports, "run");
+        exports.push(0x00);
+        uleb(&mut exports, RUN);
+        section(&mut module, 7, &exports);
+
+        let mut body = Vec::new();
+        uleb(&mut body, 1);
+        uleb(&mut body, LOCALS);
+        body.push(I32);
+        for op in &self.body {
+            op.encode(&mut body);
+        }
+
+        let mut code = Vec::new();
+        uleb(&mut code, 1);
+        uleb(&mut code, body.len() as u32);
+        code.extend_from_slice(&body);
+        section(&mut module, 10, &code);
+
+        module
+    }
+
+    /// Writes the module in the text format
+    pub fn to_wat(&self) -> String {
+        let mut wat = String::new();
+
+        writeln!(wat, "(module").unwrap();
+        writeln!(wat, "  (import \"env\" \"read\" (func $read (result i32)))").unwrap();
+        writeln!(
+            wat,
+            "  (import \"env\" \"write\" (func $write (param i32)))"
+        )
+        .unwrap();
+        writeln!(wat, "  (memory (export \"memory\") {})", self.memory_pages).unwrap();
+        writeln!(wat, "  (func (export \"run\")").unwrap();
+        writeln!(wat, "    (local $p i32)").unwrap();
+        writeln!(wat, "    (local $c i32)").unwrap();
+
+        let mut depth = 0;
+
+        // the final End closes the function, which the closing parenthesis already does
+        for op in &self.body[..self.body.len() - 1] {
+            if *op == Op::End {
+                depth -= 1;
+            }
+
+            writeln!(wat, "    {:width$}{}", "", op, width = depth * 2).unwrap();
+
+            if *op == Op::Block || *op == Op::Loop {
+                depth += 1;
+            }
+        }
+
+        writeln!(wat, "  )").unwrap();
+        writeln!(wat, ")").unwrap();
+
+        wat
+    }
+}
@@ -0,0 +1,67 @@
+(module
+  (import "env" "read" (func $read (result i32)))
+  (import "env" "write" (func $write (param i32)))
+  (memory (export "memory") 1)
+  (func (export "run")
+    (local $p i32)
+    (local $c i32)
+    local.get $p
+    local.get $p
+    i32.load8_u
+    i32.const 2
+    i32.add
+    i32.store8
+    block
+      local.get $p
+      i32.load8_u
+      i32.eqz
+      br_if 0
+      loop
+        local.get $p
+        i32.const 1
+        i32.add
+        local.set $p
+        local.get $p
+        local.get $p
+        i32.load8_u
+        i32.const 1
+        i32.add
+        i32.store8
+        local.get $p
+        i32.const -1
+        i32.add
+        local.set $p
+        local.get $p
+        local.get $p
+        i32.load8_u
+        i32.const -1
+        i32.add
+        i32.store8
+        local.get $p
+        i32.load8_u
+        br_if 0
+      end
+    end
+    local.get $p
+    i32.const 1
+    i32.add
+    local.set $p
+    local.get $p
+    i32.load8_u
+    call $write
+    call $read
+    local.set $c
+    local.get $p
+    local.get $c
+    local.get $p
+    i32.load8_u
+    local.get $c
+    i32.const 0
+    i32.ge_s
+    select
+    i32.store8
+    local.get $p
+    i32.load8_u
+    call $write
+  )
+)
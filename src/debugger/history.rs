@@ -0,0 +1,301 @@
+/// A record of everything the debugger has run, so it can run backwards
+///
+/// Every step logs what it overwrote: the program counter, the data pointer, the old value of the
+/// cell it wrote to, and whether it read or printed a byte. Only the latest steps stay in the log.
+/// Older ones are rebuilt when they're needed, by going back to a checkpoint of the whole machine
+/// and running forwards again. Bytes that were read are kept so that gives the same result, and
+/// bytes that were already printed aren't printed a second time
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::ir;
+
+/// How many steps the log keeps before dropping the oldest
+const LOG_LIMIT: usize = 1 << 18;
+
+/// How many steps apart checkpoints start out
+const CHECKPOINT_INTERVAL: u64 = 1 << 16;
+
+/// How many checkpoints are kept, before every other one is dropped and the interval doubles
+const CHECKPOINT_LIMIT: usize = 64;
+
+/// What a single step changed
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    program_counter: usize,
+    data_pointer: usize,
+    /// The cell that was written to, with the value it had before
+    write: Option<(usize, u8)>,
+    read: bool,
+    printed: bool,
+}
+
+/// The whole machine, as it was after some number of steps
+struct Checkpoint {
+    steps: u64,
+    program_counter: usize,
+    data_pointer: usize,
+    reads: usize,
+    writes: usize,
+    cells: Box<[u8]>,
+}
+
+/// A cell that a step wrote to, with its value before and after
+#[derive(Debug, Clone, Copy)]
+pub struct CellWrite {
+    pub cell: usize,
+    pub before: u8,
+    pub after: u8,
+}
+
+/// A step that was taken back
+#[derive(Debug, Clone, Copy)]
+pub struct Undone {
+    pub write: Option<CellWrite>,
+}
+
+pub struct History {
+    log: VecDeque<Entry>,
+    checkpoints: Vec<Checkpoint>,
+    /// How many steps apart new checkpoints are
+    interval: u64,
+    /// How many steps it took to get to where the vm is
+    steps: u64,
+    /// Every byte read so far, or None where the input had ended
+    inputs: Vec<Option<u8>>,
+    /// How many of `inputs` have been read to get to where the vm is
+    reads: usize,
+    /// How many bytes have been output to get to where the vm is
+    writes: usize,
+    /// How many bytes were actually printed, which can be more than `writes` after going back
+    printed: usize,
+}
+
+impl History {
+    /// Starts recording from where a vm is now
+    pub fn new(vm: &ir::Vm) -> Self {
+        let mut history = History {
+            log: VecDeque::new(),
+            checkpoints: Vec::new(),
+            interval: CHECKPOINT_INTERVAL,
+            steps: 0,
+            inputs: Vec::new(),
+            reads: 0,
+            writes: 0,
+            printed: 0,
+        };
+        history.checkpoint(vm);
+
+        history
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn checkpoint(&mut self, vm: &ir::Vm) {
+        self.checkpoints.push(Checkpoint {
+            steps: self.steps,
+            program_counter: vm.program_counter(),
+            data_pointer: vm.data_pointer(),
+            reads: self.reads,
+            writes: self.writes,
+            cells: vm.cells().into(),
+        });
+
+        if self.checkpoints.len() > CHECKPOINT_LIMIT {
+            // thinning them out keeps the first one, so there's always somewhere to start from
+            let mut idx = 0;
+            self.checkpoints.retain(|_| {
+                idx += 1;
+                idx % 2 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    /// Runs the next instruction, which mustn't move the data pointer off the tape, and returns
+    /// the byte it printed if it printed one
+    ///
+    /// Steps that were taken before read the same bytes they did then, and don't print again
+    pub fn step(
+        &mut self,
+        vm: &mut ir::Vm,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<Option<u8>, Box<dyn Error>> {
+        let program_counter = vm.program_counter();
+        let data_pointer = vm.data_pointer();
+        let instr = vm.program()[program_counter].clone();
+
+        let written = match instr {
+            ir::Instruction::IncrementByte(_) | ir::Instruction::ReadByte => Some(data_pointer),
+            ir::Instruction::IncrementPointerAndByte(inc, _) => {
+                Some((data_pointer as isize + inc as isize) as usize)
+            }
+            _ => None,
+        };
+        let write = written.map(|cell| (cell, vm.cells()[cell]));
+
+        let read = matches!(instr, ir::Instruction::ReadByte);
+        let mut printed = Vec::new();
+
+        if read {
+            if self.reads == self.inputs.len() {
+                let mut byte = [0];
+                let byte = match input.read(&mut byte)? {
+                    0 => None,
+                    _ => Some(byte[0]),
+                };
+                self.inputs.push(byte);
+            }
+
+            let mut replayed = self.inputs[self.reads].as_slice();
+            vm.step(&mut replayed, &mut printed)?;
+            self.reads += 1;
+        } else {
+            vm.step(input, &mut printed)?;
+        }
+
+        let mut shown = None;
+        if let Some(&byte) = printed.last() {
+            self.writes += 1;
+
+            if self.writes > self.printed {
+                output.write_all(&printed)?;
+                self.printed = self.writes;
+                shown = Some(byte);
+            }
+        }
+
+        self.log.push_back(Entry {
+            program_counter,
+            data_pointer,
+            write,
+            read,
+            printed: !printed.is_empty(),
+        });
+        if self.log.len() > LOG_LIMIT {
+            self.log.pop_front();
+        }
+
+        self.steps += 1;
+
+        let latest = self
+            .checkpoints
+            .last()
+            .map_or(0, |checkpoint| checkpoint.steps);
+        if self.steps >= latest + self.interval {
+            self.checkpoint(vm);
+        }
+
+        Ok(shown)
+    }
+
+    /// Takes back the latest step, or returns None if the vm is at the start of the program
+    pub fn undo(&mut self, vm: &mut ir::Vm) -> Result<Option<Undone>, Box<dyn Error>> {
+        if self.steps == 0 {
+            return Ok(None);
+        }
+
+        if self.log.is_empty() {
+            self.rebuild(vm)?;
+        }
+
+        let entry = self
+            .log
+            .pop_back()
+            .expect("a rebuilt log has the latest step");
+        let write = entry.write.map(|(cell, before)| {
+            let after = std::mem::replace(&mut vm.cells_mut()[cell], before);
+            CellWrite {
+                cell,
+                before,
+                after,
+            }
+        });
+
+        vm.resume_at(entry.program_counter, entry.data_pointer);
+        self.reads -= entry.read as usize;
+        self.writes -= entry.printed as usize;
+        self.steps -= 1;
+
+        Ok(Some(Undone { write }))
+    }
+
+    /// Refills the log up to the vm's current step, by running forwards from the checkpoint
+    /// before it
+    fn rebuild(&mut self, vm: &mut ir::Vm) -> Result<(), Box<dyn Error>> {
+        let target = self.steps;
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|checkpoint| checkpoint.steps < target)
+            .expect("there's always a checkpoint at the start");
+
+        vm.cells_mut().copy_from_slice(&checkpoint.cells);
+        vm.resume_at(checkpoint.program_counter, checkpoint.data_pointer);
+        self.steps = checkpoint.steps;
+        self.reads = checkpoint.reads;
+        self.writes = checkpoint.writes;
+
+        // everything this reads or prints was already read or printed, so none of it is real
+        while self.steps < target {
+            self.step(vm, &mut io::empty(), &mut io::sink())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck;
+
+    #[test]
+    fn goes_back_past_the_log_from_a_checkpoint() {
+        // multiplies 255 by 255 the slow way, printing as it goes
+        let source = b",>,<[>[>+>+<<-]>>[<<+>>-]<<<.-]";
+        let program = ir::transform(&brainfuck::parse(source.iter().copied())).unwrap();
+        let interval = CHECKPOINT_INTERVAL as usize;
+        // far enough back that the log runs out, landing between the second and third checkpoint
+        let ahead = LOG_LIMIT + 2 * interval + interval / 2;
+        let back = LOG_LIMIT + interval;
+
+        let mut vm = ir::Vm::new(program.clone());
+        let mut history = History::new(&vm);
+        let (mut input, mut output): (&[u8], _) = (&[255, 255], Vec::new());
+        for _ in 0..ahead {
+            history.step(&mut vm, &mut input, &mut output).unwrap();
+        }
+        assert!(!vm.is_finished());
+        let (cells, printed) = (vm.cells().to_vec(), output.len());
+
+        for _ in 0..back {
+            assert!(history.undo(&mut vm).unwrap().is_some());
+        }
+
+        let mut fresh = ir::Vm::new(program);
+        let mut input: &[u8] = &[255, 255];
+        for _ in 0..ahead - back {
+            fresh.step(&mut input, &mut io::sink()).unwrap();
+        }
+        assert_eq!(history.steps(), (ahead - back) as u64);
+        assert_eq!(vm.program_counter(), fresh.program_counter());
+        assert_eq!(vm.data_pointer(), fresh.data_pointer());
+        assert_eq!(vm.cells(), fresh.cells());
+
+        // input comes from what was read before, and nothing is printed a second time
+        for _ in 0..back {
+            history
+                .step(&mut vm, &mut io::empty(), &mut output)
+                .unwrap();
+        }
+        assert_eq!(vm.cells(), &cells[..]);
+        assert_eq!(output.len(), printed);
+    }
+}
@@ -0,0 +1,680 @@
+#![forbid(unsafe_code)]
+
+/// An interactive debugger for brainfuck, driven by commands read one line at a time
+///
+/// Programs run as BFR IR, so a step can cover a whole run of `+`s or `>`s, but every instruction
+/// knows where in the source it came from. Every `#` in the source is a breakpoint from the start.
+/// The program's own output goes to the same place as the debugger's, so it shows up as it's printed.
+/// Everything that runs is recorded, so the program can also run backwards
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+use crate::{brainfuck, ir, tape};
+
+use self::history::History;
+
+mod history;
+
+const HELP: &str = "\
+step [n]              (s) runs the next instruction, or the next n
+continue              (c) runs until a breakpoint, a watchpoint or the end of the program
+reverse-step [n]     (rs) takes back the last instruction, or the last n
+reverse-continue [n] (rc) runs backwards until a breakpoint, a watchpoint, the last write to
+                          cell n if it's given, or the start of the program
+where                 (w) shows the next instruction, where it is in the source and the tape
+tape [from..to]       (t) shows cells, around the data pointer unless a range is given
+break <l>[:<c>]       (b) stops before the code at line l, column c
+watch <n> [v]             stops when cell n changes, or only when it changes to v
+delete <id>           (d) removes a breakpoint or watchpoint
+info                  (i) lists breakpoints and watchpoints
+help                  (h) shows this
+quit                  (q) stops debugging
+
+Output isn't taken back when running backwards, and isn't printed twice when running forwards
+over it again. Input that was read is read again the same way
+";
+
+/// How many cells on each side of the data pointer `tape` shows by default
+const TAPE_CONTEXT: usize = 8;
+
+/// How many cells on each side of the data pointer are shown along with the source
+const WHERE_CONTEXT: usize = 4;
+
+/// A line and column in the source, both starting at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stop {
+    /// Stops before running an IR instruction, which is a `#` marker if `marker` is set
+    Breakpoint { instruction: usize, marker: bool },
+    /// Stops after a cell changes, to `value` if there is one
+    Watchpoint { cell: usize, value: Option<u8> },
+}
+
+/// The id, cell and value of a watchpoint
+type Watch = (usize, usize, Option<u8>);
+
+pub struct Debugger<'a> {
+    vm: ir::Vm,
+    history: History,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    /// The source, split into lines
+    lines: Vec<Vec<u8>>,
+    /// Where each brainfuck instruction is in the source
+    positions: Vec<Position>,
+    /// The first brainfuck instruction of each IR instruction, from ir::source_map
+    source_map: Vec<usize>,
+    /// Breakpoints and watchpoints, by the id they're shown and deleted with
+    stops: BTreeMap<usize, Stop>,
+    next_id: usize,
+    /// Whether the program's output left a line unfinished
+    mid_line: bool,
+}
+
+impl<'a> Debugger<'a> {
+    /// Debugs a program from its source, which reads from `input` and writes to `output`
+    pub fn new(
+        source: &[u8],
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+    ) -> Result<Self, ir::TransformError> {
+        let (code, _) = brainfuck::split_input(source);
+        let instructions = brainfuck::parse(code.iter().copied());
+        let program = ir::transform(&instructions)?;
+        let source_map = ir::source_map(&instructions, &program);
+
+        let mut positions = Vec::with_capacity(instructions.len());
+        let mut markers = Vec::new();
+        let mut position = Position { line: 1, column: 1 };
+
+        for &byte in code {
+            match byte {
+                b'+' | b'-' | b'>' | b'<' | b'.' | b',' | b'[' | b']' => positions.push(position),
+                // a marker stops before whatever comes after it
+                b'#' => markers.push(positions.len()),
+                _ => (),
+            }
+
+            if byte == b'\n' {
+                position.line += 1;
+                position.column = 1;
+            } else {
+                position.column += 1;
+            }
+        }
+
+        let vm = ir::Vm::new(program);
+        let mut debugger = Debugger {
+            history: History::new(&vm),
+            vm,
+            input,
+            output,
+            lines: source.split(|&byte| byte == b'\n').map(Vec::from).collect(),
+            positions,
+            source_map,
+            stops: BTreeMap::new(),
+            next_id: 1,
+            mid_line: false,
+        };
+
+        for marker in markers {
+            if let Some(instruction) = debugger.instruction_of(marker) {
+                debugger.add_stop(Stop::Breakpoint {
+                    instruction,
+                    marker: true,
+                });
+            }
+        }
+
+        Ok(debugger)
+    }
+
+    fn add_stop(&mut self, stop: Stop) -> usize {
+        let id = self.next_id;
+        self.stops.insert(id, stop);
+        self.next_id += 1;
+
+        id
+    }
+
+    /// The IR instruction that a brainfuck instruction ended up in
+    fn instruction_of(&self, bf_instruction: usize) -> Option<usize> {
+        if bf_instruction >= self.positions.len() {
+            return None;
+        }
+
+        Some(
+            self.source_map
+                .partition_point(|&start| start <= bf_instruction)
+                - 1,
+        )
+    }
+
+    /// Where an IR instruction starts and ends in the source
+    fn span_of(&self, instruction: usize) -> (Position, Position) {
+        let start = self.source_map[instruction];
+        let end = self.source_map[instruction + 1];
+
+        (self.positions[start], self.positions[end - 1])
+    }
+
+    /// Runs a single instruction, unless it would move the data pointer off the tape
+    fn step_once(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.vm.leaves_tape() {
+            return Err("stopped, the data pointer would move off the tape".into());
+        }
+
+        if let Some(byte) = self.history.step(&mut self.vm, self.input, self.output)? {
+            self.mid_line = byte != b'\n';
+        }
+
+        Ok(())
+    }
+
+    /// The id of the breakpoint on each instruction, and every watchpoint
+    fn active_stops(&self) -> (Vec<Option<usize>>, Vec<Watch>) {
+        let mut breakpoints = vec![None; self.vm.program().len() + 1];
+        let mut watchpoints = Vec::new();
+
+        for (&id, stop) in self.stops.iter().rev() {
+            match *stop {
+                Stop::Breakpoint { instruction, .. } => breakpoints[instruction] = Some(id),
+                Stop::Watchpoint { cell, value } => watchpoints.push((id, cell, value)),
+            }
+        }
+
+        (breakpoints, watchpoints)
+    }
+
+    /// Runs up to `count` instructions, returning why it stopped early if a breakpoint or
+    /// watchpoint was hit
+    fn step(&mut self, count: usize) -> Result<Option<String>, Box<dyn Error>> {
+        let (breakpoints, watchpoints) = self.active_stops();
+        let mut before = vec![0; watchpoints.len()];
+
+        for _ in 0..count {
+            if self.vm.is_finished() {
+                break;
+            }
+
+            for (old, &(_, cell, _)) in before.iter_mut().zip(&watchpoints) {
+                *old = self.vm.cells()[cell];
+            }
+
+            self.step_once()?;
+
+            for (&old, &(id, cell, value)) in before.iter().zip(&watchpoints) {
+                let new = self.vm.cells()[cell];
+
+                if new != old && value.is_none_or(|value| value == new) {
+                    return Ok(Some(format!(
+                        "watchpoint {}: cell {} changed from {} to {}",
+                        id, cell, old, new
+                    )));
+                }
+            }
+
+            if let Some(id) = breakpoints[self.vm.program_counter()] {
+                return Ok(Some(format!("breakpoint {}", id)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Takes back up to `count` instructions, returning why it stopped early if a breakpoint or
+    /// watchpoint was hit, `cell` was written to, or the program is back at its start
+    fn reverse(
+        &mut self,
+        count: usize,
+        cell: Option<usize>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let (breakpoints, watchpoints) = self.active_stops();
+
+        for _ in 0..count {
+            let undone = match self.history.undo(&mut self.vm)? {
+                Some(undone) => undone,
+                None => return Ok(Some("at the start of the program".to_string())),
+            };
+
+            if let Some(write) = undone.write {
+                if cell == Some(write.cell) {
+                    return Ok(Some(format!(
+                        "cell {} was last written here, from {} to {}",
+                        write.cell, write.before, write.after
+                    )));
+                }
+
+                for &(id, watched, value) in &watchpoints {
+                    if watched == write.cell
+                        && write.before != write.after
+                        && value.is_none_or(|value| value == write.after)
+                    {
+                        return Ok(Some(format!(
+                            "watchpoint {}: cell {} changed here from {} to {}",
+                            id, watched, write.before, write.after
+                        )));
+                    }
+                }
+            }
+
+            if let Some(id) = breakpoints[self.vm.program_counter()] {
+                return Ok(Some(format!("breakpoint {}", id)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Writes a source line, with carets under the columns from `from` to `to`
+    fn show_source(&mut self, line: usize, from: usize, to: usize) -> io::Result<()> {
+        let text = &self.lines[line - 1];
+        // tabs stay tabs, so the carets line up however wide they are
+        let indent: String = text
+            .iter()
+            .take(from - 1)
+            .map(|&byte| if byte == b'\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(
+            self.output,
+            "{:>5} | {}",
+            line,
+            String::from_utf8_lossy(text).trim_end()
+        )?;
+        writeln!(
+            self.output,
+            "      | {}{}",
+            indent,
+            "^".repeat(to + 1 - from)
+        )
+    }
+
+    fn show_location(&mut self) -> io::Result<()> {
+        let pc = self.vm.program_counter();
+        let pointer = self.vm.data_pointer();
+        let cell = self.vm.cells()[pointer];
+
+        let instr = match self.vm.program().get(pc) {
+            Some(instr) => instr.clone(),
+            None => return writeln!(self.output, "finished, pointer at {} (= {})", pointer, cell),
+        };
+
+        let (start, end) = self.span_of(pc);
+        writeln!(
+            self.output,
+            "{}:{}: {:?}, pointer at {} (= {}), after {} step{}",
+            start.line,
+            start.column,
+            instr,
+            pointer,
+            cell,
+            self.history.steps(),
+            if self.history.steps() == 1 { "" } else { "s" }
+        )?;
+
+        if start.line == end.line {
+            self.show_source(start.line, start.column, end.column)?;
+        } else {
+            // only the first line of an instruction spread over several
+            let width = self.lines[start.line - 1].len();
+            self.show_source(start.line, start.column, width.max(start.column))?;
+        }
+
+        self.show_cells(
+            pointer.saturating_sub(WHERE_CONTEXT),
+            pointer + WHERE_CONTEXT + 1,
+        )
+    }
+
+    /// Writes cells side by side, with the one under the data pointer in brackets
+    fn show_cells(&mut self, from: usize, to: usize) -> io::Result<()> {
+        tape::show(
+            self.vm.cells(),
+            self.vm.data_pointer(),
+            from,
+            to,
+            self.output,
+        )
+    }
+
+    fn show_tape(&mut self, from: usize, to: usize) -> io::Result<()> {
+        let pointer = self.vm.data_pointer();
+        let cells = self.vm.cells();
+        let to = to.min(cells.len());
+
+        for (idx, cell) in cells.iter().enumerate().take(to).skip(from) {
+            let marker = if idx == pointer { ">" } else { " " };
+            writeln!(self.output, "{}{:6}: {:3}", marker, idx, cell)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_stops(&mut self) -> io::Result<()> {
+        if self.stops.is_empty() {
+            return writeln!(self.output, "no breakpoints or watchpoints");
+        }
+
+        for (id, stop) in self.stops.clone() {
+            match stop {
+                Stop::Breakpoint {
+                    instruction,
+                    marker,
+                } => {
+                    let (start, _) = self.span_of(instruction);
+                    let kind = if marker { "marker" } else { "breakpoint" };
+                    writeln!(
+                        self.output,
+                        "{:>3}  {} at {}:{}",
+                        id, kind, start.line, start.column
+                    )?;
+                }
+                Stop::Watchpoint { cell, value: None } => {
+                    writeln!(self.output, "{:>3}  watchpoint on cell {}", id, cell)?;
+                }
+                Stop::Watchpoint {
+                    cell,
+                    value: Some(value),
+                } => {
+                    writeln!(
+                        self.output,
+                        "{:>3}  watchpoint on cell {} becoming {}",
+                        id, cell, value
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses `line` or `line:column`
+    fn parse_position(arg: &str) -> Option<Position> {
+        let (line, column) = match arg.find(':') {
+            Some(idx) => (arg[..idx].parse().ok()?, arg[idx + 1..].parse().ok()?),
+            None => (arg.parse().ok()?, 1),
+        };
+
+        Some(Position { line, column })
+    }
+
+    /// Runs one command, returning false once debugging should stop
+    fn command(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let arg = words.next();
+
+        match command {
+            "s" | "step" | "c" | "continue" => {
+                let count = match (command, arg.map(str::parse)) {
+                    ("c", _) | ("continue", _) => usize::MAX,
+                    (_, Some(Ok(count))) => count,
+                    (_, Some(Err(_))) => {
+                        writeln!(self.output, "step takes a number of instructions")?;
+                        return Ok(true);
+                    }
+                    (_, None) => 1,
+                };
+
+                let result = self.step(count);
+
+                if self.mid_line {
+                    writeln!(self.output)?;
+                    self.mid_line = false;
+                }
+
+                match result {
+                    Ok(Some(reason)) => writeln!(self.output, "{}", reason)?,
+                    Ok(None) => (),
+                    Err(err) => writeln!(self.output, "{}", err)?,
+                }
+                self.show_location()?;
+            }
+            "rs" | "reverse-step" | "rc" | "reverse-continue" => {
+                let (count, cell) = match (command, arg.map(str::parse)) {
+                    (_, Some(Err(_))) => {
+                        writeln!(self.output, "{} takes a number", command)?;
+                        return Ok(true);
+                    }
+                    ("rs", arg) | ("reverse-step", arg) => (arg.map_or(Ok(1), |arg| arg)?, None),
+                    (_, arg) => (usize::MAX, arg.transpose()?),
+                };
+
+                match self.reverse(count, cell) {
+                    Ok(Some(reason)) => writeln!(self.output, "{}", reason)?,
+                    Ok(None) => (),
+                    Err(err) => writeln!(self.output, "{}", err)?,
+                }
+                self.show_location()?;
+            }
+            "w" | "where" => self.show_location()?,
+            "t" | "tape" => {
+                let (from, to) = match arg {
+                    Some(arg) => match tape::parse_range(arg) {
+                        Some(range) => range,
+                        None => {
+                            writeln!(self.output, "tape takes a range of cells, like 0..20")?;
+                            return Ok(true);
+                        }
+                    },
+                    None => {
+                        let pointer = self.vm.data_pointer();
+                        (
+                            pointer.saturating_sub(TAPE_CONTEXT),
+                            pointer + TAPE_CONTEXT + 1,
+                        )
+                    }
+                };
+
+                self.show_tape(from, to)?;
+            }
+            "b" | "break" => {
+                let position = match arg.and_then(Self::parse_position) {
+                    Some(position) => position,
+                    None => {
+                        writeln!(
+                            self.output,
+                            "break takes a line, or a line and column like 3:14"
+                        )?;
+                        return Ok(true);
+                    }
+                };
+
+                // the first instruction at or after the position
+                let bf_instruction = self.positions.partition_point(|&at| at < position);
+
+                match self.instruction_of(bf_instruction) {
+                    Some(instruction) => {
+                        let id = self.add_stop(Stop::Breakpoint {
+                            instruction,
+                            marker: false,
+                        });
+                        let (start, _) = self.span_of(instruction);
+                        writeln!(
+                            self.output,
+                            "breakpoint {} at {}:{}",
+                            id, start.line, start.column
+                        )?;
+                    }
+                    None => writeln!(
+                        self.output,
+                        "no code at or after {}:{}",
+                        position.line, position.column
+                    )?,
+                }
+            }
+            "watch" => {
+                let cell = arg.and_then(|arg| arg.parse().ok());
+                let value = words.next().map(str::parse);
+
+                match (cell, value) {
+                    (Some(cell), None) if cell < self.vm.cells().len() => {
+                        let id = self.add_stop(Stop::Watchpoint { cell, value: None });
+                        writeln!(self.output, "watchpoint {} on cell {}", id, cell)?;
+                    }
+                    (Some(cell), Some(Ok(value))) if cell < self.vm.cells().len() => {
+                        let id = self.add_stop(Stop::Watchpoint {
+                            cell,
+                            value: Some(value),
+                        });
+                        writeln!(
+                            self.output,
+                            "watchpoint {} on cell {} becoming {}",
+                            id, cell, value
+                        )?;
+                    }
+                    _ => writeln!(
+                        self.output,
+                        "watch takes a cell on the tape, and optionally a value from 0 to 255"
+                    )?,
+                }
+            }
+            "d" | "delete" => match arg.and_then(|arg| arg.parse().ok()) {
+                Some(id) if self.stops.remove(&id).is_some() => {
+                    writeln!(self.output, "deleted {}", id)?;
+                }
+                _ => writeln!(
+                    self.output,
+                    "delete takes the id of a breakpoint or watchpoint"
+                )?,
+            },
+            "i" | "info" => self.show_stops()?,
+            "h" | "help" => write!(self.output, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(self.output, "unknown command {}, try help", command)?,
+        }
+
+        Ok(true)
+    }
+
+    /// Reads and runs commands until they run out or one of them quits
+    pub fn run(&mut self, commands: &mut dyn BufRead) -> Result<(), Box<dyn Error>> {
+        self.show_location()?;
+
+        loop {
+            write!(self.output, "(bfr) ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if commands.read_line(&mut line)? == 0 || !self.command(&line)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the debugger has stopped in the source
+    fn stopped_at(debugger: &Debugger) -> (usize, usize) {
+        let (start, _) = debugger.span_of(debugger.vm.program_counter());
+
+        (start.line, start.column)
+    }
+
+    #[test]
+    fn markers_stop_before_what_comes_after_them() {
+        let (mut input, mut output) = (io::empty(), Vec::new());
+        let mut debugger = Debugger::new(b"++#>+\n+++[-]", &mut input, &mut output).unwrap();
+
+        assert_eq!(debugger.step(100).unwrap().as_deref(), Some("breakpoint 1"));
+        assert_eq!(stopped_at(&debugger), (1, 4));
+        assert_eq!(debugger.step(100).unwrap(), None);
+        assert!(debugger.vm.is_finished());
+    }
+
+    #[test]
+    fn breakpoints_by_line_and_column() {
+        let (mut input, mut output) = (io::empty(), Vec::new());
+        let mut debugger = Debugger::new(b"+>+\n\t+++[-]", &mut input, &mut output).unwrap();
+
+        debugger.command("break 2:5").unwrap();
+        assert_eq!(debugger.step(100).unwrap().as_deref(), Some("breakpoint 1"));
+        assert_eq!(stopped_at(&debugger), (2, 5));
+        assert_eq!(debugger.vm.cells()[1], 4);
+
+        // the loop comes back to it every time around
+        assert_eq!(debugger.step(100).unwrap().as_deref(), Some("breakpoint 1"));
+        assert_eq!(debugger.vm.cells()[1], 3);
+
+        // with no column, a breakpoint goes on the first instruction of the line
+        debugger.command("delete 1").unwrap();
+        debugger.command("break 1").unwrap();
+        assert_eq!(debugger.step(100).unwrap(), None);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_write() {
+        let (mut input, mut output) = (io::empty(), Vec::new());
+        let mut debugger = Debugger::new(b"+>+++[-]>+", &mut input, &mut output).unwrap();
+
+        debugger.command("watch 1 0").unwrap();
+        assert_eq!(
+            debugger.step(100).unwrap().as_deref(),
+            Some("watchpoint 1: cell 1 changed from 1 to 0")
+        );
+
+        debugger.command("delete 1").unwrap();
+        debugger.command("watch 2").unwrap();
+        assert_eq!(
+            debugger.step(100).unwrap().as_deref(),
+            Some("watchpoint 2: cell 2 changed from 0 to 1")
+        );
+    }
+
+    #[test]
+    fn stops_before_leaving_the_tape() {
+        let (mut input, mut output) = (io::empty(), Vec::new());
+        let mut debugger = Debugger::new(b"+<", &mut input, &mut output).unwrap();
+
+        assert!(debugger.step(100).is_err());
+        assert_eq!(debugger.vm.program_counter(), 1);
+    }
+
+    #[test]
+    fn reverse_continue_finds_the_last_write_to_a_cell() {
+        let (mut input, mut output) = (io::empty(), Vec::new());
+        let mut debugger = Debugger::new(b"+++>++<-\n>>+", &mut input, &mut output).unwrap();
+
+        debugger.step(100).unwrap();
+        assert_eq!(
+            debugger.reverse(100, Some(0)).unwrap().as_deref(),
+            Some("cell 0 was last written here, from 3 to 2")
+        );
+        // `<-` is a single instruction
+        assert_eq!(stopped_at(&debugger), (1, 7));
+        assert_eq!(
+            debugger.reverse(100, None).unwrap().as_deref(),
+            Some("at the start of the program")
+        );
+        assert_eq!(debugger.vm.cells()[..3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn going_back_replays_input_and_doesnt_print_twice() {
+        let mut input: &[u8] = b"ab";
+        let mut output = Vec::new();
+        let mut debugger = Debugger::new(b",.>,.", &mut input, &mut output).unwrap();
+
+        debugger.step(100).unwrap();
+        debugger.reverse(100, None).unwrap();
+        debugger.step(100).unwrap();
+        assert_eq!(debugger.vm.cells()[..2], *b"ab");
+
+        drop(debugger);
+        assert_eq!(output, b"ab");
+    }
+}
@@ -155,6 +155,44 @@ pub fn transform(instructions: &[BfInstruction]) -> Result<Vec<Instruction>, Tra
     Ok(transformed)
 }
 
+/// Finds which brainfuck instructions each IR instruction came from, as the index of the first one
+///
+/// Has an extra entry at the end, with the number of brainfuck instructions
+pub fn source_map(original: &[BfInstruction], transformed: &[Instruction]) -> Vec<usize> {
+    let is_byte_op = |instr: &BfInstruction| {
+        *instr == BfInstruction::IncrementByte || *instr == BfInstruction::DecrementByte
+    };
+    let is_pointer_op = |instr: &BfInstruction| {
+        *instr == BfInstruction::IncrementPointer || *instr == BfInstruction::DecrementPointer
+    };
+    // increments were combined greedily, so each one takes the whole run
+    let run = |start: usize, is_op: &dyn Fn(&BfInstruction) -> bool| {
+        start
+            + original[start..]
+                .iter()
+                .take_while(|instr| is_op(instr))
+                .count()
+    };
+
+    let mut map = Vec::with_capacity(transformed.len() + 1);
+    let mut idx = 0;
+
+    for instr in transformed {
+        map.push(idx);
+        idx = match instr {
+            Instruction::IncrementByte(_) => run(idx, &is_byte_op),
+            Instruction::IncrementPointer(_) => run(idx, &is_pointer_op),
+            Instruction::IncrementPointerAndByte(_, _) => {
+                run(run(idx, &is_pointer_op), &is_byte_op)
+            }
+            _ => idx + 1,
+        };
+    }
+    map.push(idx);
+
+    map
+}
+
 /// A BFR IR virtual machine
 ///
 /// Slightly more optimized than the pure Brainfuck vm
@@ -192,6 +230,58 @@ impl Vm {
         &self.cells[self.data_pointer]
     }
 
+    /// The program being executed
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// The index of the next instruction to be executed
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    pub fn data_pointer(&self) -> usize {
+        self.data_pointer
+    }
+
+    pub fn cells(&self) -> &[u8] {
+        &self.cells
+    }
+
+    /// Lets something else run part of the program against this vm's cells
+    pub fn cells_mut(&mut self) -> &mut [u8] {
+        &mut self.cells
+    }
+
+    /// Swaps in another program to run from its start, keeping the tape and the data pointer
+    pub fn load(&mut self, program: Vec<Instruction>) {
+        self.program = program;
+        self.program_counter = 0;
+    }
+
+    /// Continues execution from somewhere else, after part of the program was run by someone else
+    pub fn resume_at(&mut self, program_counter: usize, data_pointer: usize) {
+        self.program_counter = program_counter;
+        self.data_pointer = data_pointer;
+    }
+
+    /// Whether the whole program has been executed
+    pub fn is_finished(&self) -> bool {
+        self.program_counter >= self.program.len()
+    }
+
+    /// Whether the next instruction would move the data pointer off the tape, which would panic
+    pub fn leaves_tape(&self) -> bool {
+        let inc = match self.program.get(self.program_counter) {
+            Some(Instruction::IncrementPointer(inc)) => *inc,
+            Some(Instruction::IncrementPointerAndByte(inc, _)) => *inc,
+            _ => return false,
+        };
+
+        let pointer = self.data_pointer as isize + inc as isize;
+        pointer < 0 || pointer >= self.cells.len() as isize
+    }
+
     /// Executes a single BFR IR instruction
     pub fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), VmError> {
         let pc = match self.program[self.program_counter] {
@@ -229,7 +319,7 @@ impl Vm {
             }
             Instruction::ReadByte => {
                 input
-                    .read(&mut self.cells[self.data_pointer..1])
+                    .read(&mut self.cells[self.data_pointer..=self.data_pointer])
                     .context(FailedToRead)?;
                 self.program_counter.wrapping_add(1)
             }
@@ -260,10 +350,28 @@ impl Vm {
 
     /// Runs the program to end
     pub fn vm_loop(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), VmError> {
-        while self.program_counter < self.program.len() {
+        while !self.is_finished() {
             self.step(input, output)?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck;
+
+    #[test]
+    fn reads_past_cell_0_and_leaves_the_cell_alone_at_the_end_of_input() {
+        let program = transform(&brainfuck::parse(b">,.>+,.,.".iter().copied())).unwrap();
+        let mut output = Vec::new();
+
+        Vm::new(program)
+            .vm_loop(&mut &b"a"[..], &mut output)
+            .unwrap();
+
+        assert_eq!(output, [b'a', 1, 1]);
+    }
+}
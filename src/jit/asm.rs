@@ -0,0 +1,215 @@
+/// Writes the code jit::assemble generates as assembly source, for GAS or NASM
+///
+/// The output is a whole program like the ones `bfr compile` builds: `_start` runs `bf_main` on a
+/// tape in .bss, and exits once it's done. Each line is commented with the bytes x86::Emitter
+/// generates for it, although assemblers are free to pick shorter encodings
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::disasm::{decode, hex_bytes, Operand};
+use super::{assemble, Io};
+use crate::ir::Instruction;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Syntax {
+    /// GNU as, with `.intel_syntax noprefix`
+    Gas,
+    Nasm,
+}
+
+impl Syntax {
+    fn comment(self) -> &'static str {
+        match self {
+            Syntax::Gas => "#",
+            Syntax::Nasm => ";",
+        }
+    }
+}
+
+/// The brainfuck an IR instruction came from
+fn brainfuck(instr: &Instruction) -> String {
+    fn repeat(positive: char, negative: char, count: i32) -> String {
+        let c = if count < 0 { negative } else { positive };
+        std::iter::repeat_n(c, count.unsigned_abs() as usize).collect()
+    }
+
+    match *instr {
+        Instruction::IncrementPointer(inc) => repeat('>', '<', inc),
+        Instruction::IncrementByte(inc) => repeat('+', '-', inc),
+        Instruction::IncrementPointerAndByte(ptr_inc, byte_inc) => {
+            repeat('>', '<', ptr_inc) + &repeat('+', '-', byte_inc)
+        }
+        Instruction::OutputByte => String::from("."),
+        Instruction::ReadByte => String::from(","),
+        Instruction::JumpForwardsIfZero(_) => String::from("["),
+        Instruction::JumpBackwardsIfNotZero(_) => String::from("]"),
+    }
+}
+
+fn operand(operand: &Operand, syntax: Syntax, labels: &BTreeMap<usize, Vec<String>>) -> String {
+    let label = |target: i64| labels[&(target as usize)][0].clone();
+
+    match (operand, syntax) {
+        (Operand::Register(name), _) => name.to_string(),
+        (Operand::BytePointer(base), Syntax::Gas) => format!("byte ptr [{}]", base),
+        (Operand::BytePointer(base), Syntax::Nasm) => format!("byte [{}]", base),
+        (Operand::RipRelative(_, target), Syntax::Gas) => format!("[rip + {}]", label(*target)),
+        (Operand::RipRelative(_, target), Syntax::Nasm) => format!("[rel {}]", label(*target)),
+        (Operand::Immediate(imm), _) => imm.to_string(),
+        (Operand::Target(target), _) => label(*target),
+    }
+}
+
+fn line(source: &mut String, text: &str) {
+    writeln!(source, "    {}", text).unwrap();
+}
+
+/// Generates a program that behaves like `bfr compile`'s executables, with `tape_size` cells
+pub fn generate(instructions: &[Instruction], syntax: Syntax, tape_size: usize) -> String {
+    let assembly = assemble(instructions, Io::Syscalls);
+    let code = &assembly.code;
+    let comment = syntax.comment();
+
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let instr = decode(code, offset).expect("the decoder understands everything we emit");
+        offset += instr.length;
+        decoded.push(instr);
+    }
+
+    // loops are named after their JumpForwardsIfZero, like the symbols perf and GDB get
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut loops = Vec::new();
+    for (idx, instr) in instructions.iter().enumerate() {
+        match instr {
+            Instruction::JumpForwardsIfZero(_) => {
+                labels
+                    .entry(assembly.source_map[idx + 1])
+                    .or_default()
+                    .push(format!("bf_loop_{}", idx));
+                loops.push(idx);
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => {
+                let start = loops.pop().expect("the IR has balanced loops");
+                labels
+                    .entry(assembly.source_map[idx + 1])
+                    .or_default()
+                    .push(format!("bf_loop_{}_end", start));
+            }
+            _ => (),
+        }
+    }
+
+    // anything else that gets jumped to, which are only short jumps around output flushes
+    for instr in &decoded {
+        for op in &instr.operands {
+            if let Operand::Target(target) = op {
+                labels.entry(*target as usize).or_insert_with(|| {
+                    let prefix = if syntax == Syntax::Gas { ".L" } else { "L" };
+                    vec![format!("{}{:x}", prefix, target)]
+                });
+            }
+        }
+    }
+
+    let mut source = String::new();
+
+    writeln!(source, "{} Generated by bfr", comment).unwrap();
+    match syntax {
+        Syntax::Gas => {
+            writeln!(
+                source,
+                "# Build with: as prog.s -o prog.o && ld prog.o -o prog"
+            )
+            .unwrap();
+            line(&mut source, ".intel_syntax noprefix");
+            line(&mut source, ".text");
+            line(&mut source, ".globl _start");
+        }
+        Syntax::Nasm => {
+            writeln!(
+                source,
+                "; Build with: nasm -f elf64 prog.asm -o prog.o && ld prog.o -o prog"
+            )
+            .unwrap();
+            line(&mut source, "bits 64");
+            line(&mut source, "section .text");
+            line(&mut source, "global _start");
+        }
+    }
+
+    writeln!(source).unwrap();
+    writeln!(source, "_start:").unwrap();
+    match syntax {
+        Syntax::Gas => line(&mut source, "lea rdi, [rip + tape]"),
+        Syntax::Nasm => line(&mut source, "lea rdi, [rel tape]"),
+    }
+    line(&mut source, "call bf_main");
+    line(&mut source, &format!("{} exit(0)", comment));
+    line(&mut source, "mov eax, 60");
+    line(&mut source, "mov edi, 0");
+    line(&mut source, "syscall");
+    writeln!(source).unwrap();
+    writeln!(source, "bf_main:").unwrap();
+    line(&mut source, &format!("{} prologue", comment));
+
+    let mut next = 0;
+    for instr in &decoded {
+        for label in labels.get(&instr.offset).into_iter().flatten() {
+            writeln!(source, "{}:", label).unwrap();
+        }
+
+        while next < assembly.source_map.len() && assembly.source_map[next] <= instr.offset {
+            match instructions.get(next) {
+                Some(ir) => line(
+                    &mut source,
+                    &format!("{} {}: {}", comment, next, brainfuck(ir)),
+                ),
+                None => line(&mut source, &format!("{} epilogue", comment)),
+            }
+            next += 1;
+        }
+
+        let operands = instr
+            .operands
+            .iter()
+            .map(|op| operand(op, syntax, &labels))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let text = format!("{} {}", instr.mnemonic, operands);
+
+        line(
+            &mut source,
+            &format!(
+                "{:<40}{} {}",
+                text.trim_end(),
+                comment,
+                hex_bytes(&code[instr.offset..instr.offset + instr.length])
+            ),
+        );
+    }
+
+    writeln!(source).unwrap();
+    match syntax {
+        Syntax::Gas => {
+            line(&mut source, ".bss");
+            writeln!(source, "tape:").unwrap();
+            line(&mut source, &format!(".zero {}", tape_size));
+            writeln!(source).unwrap();
+            line(&mut source, ".section .note.GNU-stack,\"\",@progbits");
+        }
+        Syntax::Nasm => {
+            line(&mut source, "section .bss");
+            writeln!(source, "tape:").unwrap();
+            line(&mut source, &format!("resb {}", tape_size));
+            writeln!(source).unwrap();
+            line(
+                &mut source,
+                "section .note.GNU-stack noalloc noexec nowrite progbits",
+            );
+        }
+    }
+
+    source
+}
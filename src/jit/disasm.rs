@@ -0,0 +1,353 @@
+/// A tiny x86_64 decoder that understands exactly what x86::Emitter can produce
+///
+/// Anything else decodes as (bad), which is a good hint that the emitter did something weird
+use std::convert::TryInto;
+use std::fmt;
+use std::fmt::Write;
+
+use super::Assembly;
+use crate::ir;
+
+const REGISTERS_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+const REGISTERS_32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+
+// the emitter never uses a REX prefix with byte registers, so 4-7 are the legacy high bytes
+const REGISTERS_8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(&'static str),
+    /// A byte sized memory operand addressed by a 64 bit register
+    BytePointer(&'static str),
+    /// A memory operand relative to the next instruction, and the code offset it points to
+    RipRelative(i32, i64),
+    Immediate(i64),
+    /// The code offset a jump or call goes to
+    Target(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub length: usize,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(name) => write!(f, "{}", name),
+            Operand::BytePointer(base) => write!(f, "byte [{}]", base),
+            Operand::RipRelative(displacement, _) => write!(f, "[rip{:+#x}]", displacement),
+            Operand::Immediate(imm) => write!(f, "{}", imm),
+            Operand::Target(target) => write!(f, "{:#x}", target),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+
+        for (idx, operand) in self.operands.iter().enumerate() {
+            let separator = if idx == 0 { " " } else { ", " };
+            write!(f, "{}{}", separator, operand)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Rex {
+    w: bool,
+    r: u8,
+    b: u8,
+}
+
+struct ModRm {
+    mode: u8,
+    reg: u8,
+    rm: u8,
+}
+
+fn modrm(byte: u8, rex: &Rex) -> ModRm {
+    ModRm {
+        mode: byte >> 6,
+        reg: ((byte >> 3) & 0b111) | rex.r,
+        rm: (byte & 0b111) | rex.b,
+    }
+}
+
+fn i32_at(code: &[u8], at: usize) -> Option<i32> {
+    let bytes = code.get(at..at + 4)?;
+    Some(i32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decodes the instruction starting at `offset`, or returns None if the emitter couldn't have made it
+pub fn decode(code: &[u8], offset: usize) -> Option<Instruction> {
+    let mut at = offset;
+    let mut byte = *code.get(at)?;
+
+    let mut rex = Rex {
+        w: false,
+        r: 0,
+        b: 0,
+    };
+    let has_rex = byte & 0xf0 == 0x40;
+
+    if has_rex {
+        // we never emit REX.X, as we never use an index register
+        if byte & 0b10 != 0 {
+            return None;
+        }
+
+        rex.w = byte & 0b1000 != 0;
+        rex.r = (byte & 0b100) << 1;
+        rex.b = (byte & 0b1) << 3;
+        at += 1;
+        byte = *code.get(at)?;
+    }
+
+    at += 1;
+
+    let register = |idx: u8| Operand::Register(REGISTERS_64[usize::from(idx)]);
+    let register_w = |idx: u8| {
+        if rex.w {
+            Operand::Register(REGISTERS_64[usize::from(idx)])
+        } else {
+            Operand::Register(REGISTERS_32[usize::from(idx)])
+        }
+    };
+    let register_8 = |idx: u8| {
+        REGISTERS_8
+            .get(usize::from(idx))
+            .map(|&name| Operand::Register(name))
+    };
+    // [rsp] and [rbp] need a SIB byte or a displacement, which the emitter doesn't do
+    let pointer = |idx: u8| {
+        if idx & 0b111 == 4 || idx & 0b111 == 5 {
+            None
+        } else {
+            Some(Operand::BytePointer(REGISTERS_64[usize::from(idx)]))
+        }
+    };
+
+    let (mnemonic, operands) = match byte {
+        0xff | 0x8f if !rex.w => {
+            let modrm = modrm(*code.get(at)?, &rex);
+            at += 1;
+
+            let mnemonic = match (byte, modrm.mode, modrm.reg & 0b111) {
+                (0xff, 0b11, 2) => "call",
+                (0xff, 0b11, 6) => "push",
+                (0x8f, 0b11, 0) => "pop",
+                _ => return None,
+            };
+
+            (mnemonic, vec![register(modrm.rm)])
+        }
+        0x89 | 0x29 | 0x39 if rex.w => {
+            let modrm = modrm(*code.get(at)?, &rex);
+            at += 1;
+
+            if modrm.mode != 0b11 {
+                return None;
+            }
+
+            let mnemonic = match byte {
+                0x89 => "mov",
+                0x29 => "sub",
+                _ => "cmp",
+            };
+
+            (mnemonic, vec![register(modrm.rm), register(modrm.reg)])
+        }
+        0x83 | 0x81 => {
+            let modrm = modrm(*code.get(at)?, &rex);
+            at += 1;
+
+            let mnemonic = match (modrm.mode, modrm.reg & 0b111) {
+                (0b11, 0) => "add",
+                (0b11, 5) => "sub",
+                _ => return None,
+            };
+
+            let imm = if byte == 0x83 {
+                at += 1;
+                i64::from(*code.get(at - 1)? as i8)
+            } else {
+                at += 4;
+                i64::from(i32_at(code, at - 4)?)
+            };
+
+            (
+                mnemonic,
+                vec![register_w(modrm.rm), Operand::Immediate(imm)],
+            )
+        }
+        0x80 | 0x84 if !has_rex => {
+            let modrm = modrm(*code.get(at)?, &rex);
+            at += 1;
+
+            if modrm.mode != 0b11 {
+                return None;
+            }
+
+            if byte == 0x84 {
+                ("test", vec![register_8(modrm.rm)?, register_8(modrm.reg)?])
+            } else {
+                let mnemonic = match modrm.reg {
+                    0 => "add",
+                    5 => "sub",
+                    _ => return None,
+                };
+                at += 1;
+                let imm = i64::from(*code.get(at - 1)?);
+
+                (
+                    mnemonic,
+                    vec![register_8(modrm.rm)?, Operand::Immediate(imm)],
+                )
+            }
+        }
+        0x88 if !rex.w && rex.r == 0 => {
+            let modrm = modrm(*code.get(at)?, &rex);
+            at += 1;
+
+            if modrm.mode != 0b00 {
+                return None;
+            }
+
+            ("mov", vec![pointer(modrm.rm)?, register_8(modrm.reg)?])
+        }
+        0x8d if rex.w => {
+            let modrm = modrm(*code.get(at)?, &rex);
+            at += 1;
+
+            if modrm.mode != 0b00 || modrm.rm & 0b111 != 0b101 {
+                return None;
+            }
+
+            at += 4;
+            let displacement = i32_at(code, at - 4)?;
+            let target = at as i64 + i64::from(displacement);
+
+            (
+                "lea",
+                vec![
+                    register(modrm.reg),
+                    Operand::RipRelative(displacement, target),
+                ],
+            )
+        }
+        0x0f if !has_rex => {
+            let second = *code.get(at)?;
+            at += 1;
+
+            match second {
+                0xb6 => {
+                    let modrm = modrm(*code.get(at)?, &rex);
+                    at += 1;
+
+                    if modrm.mode != 0b00 {
+                        return None;
+                    }
+
+                    ("movzx", vec![register_w(modrm.reg), pointer(modrm.rm)?])
+                }
+                0x84 | 0x85 => {
+                    at += 4;
+                    let target = at as i64 + i64::from(i32_at(code, at - 4)?);
+                    let mnemonic = if second == 0x84 { "je" } else { "jne" };
+
+                    (mnemonic, vec![Operand::Target(target)])
+                }
+                0x05 => ("syscall", vec![]),
+                _ => return None,
+            }
+        }
+        0x74 | 0x75 if !has_rex => {
+            at += 1;
+            let target = at as i64 + i64::from(*code.get(at - 1)? as i8);
+            let mnemonic = if byte == 0x74 { "je" } else { "jne" };
+
+            (mnemonic, vec![Operand::Target(target)])
+        }
+        0xe8 if !has_rex => {
+            at += 4;
+            let target = at as i64 + i64::from(i32_at(code, at - 4)?);
+
+            ("call", vec![Operand::Target(target)])
+        }
+        0xc3 if !has_rex => ("ret", vec![]),
+        0xb8..=0xbf if !rex.w => {
+            at += 4;
+            let imm = i64::from(i32_at(code, at - 4)? as u32);
+
+            (
+                "mov",
+                vec![register_w((byte - 0xb8) | rex.b), Operand::Immediate(imm)],
+            )
+        }
+        _ => return None,
+    };
+
+    Some(Instruction {
+        offset,
+        length: at - offset,
+        mnemonic,
+        operands,
+    })
+}
+
+pub(super) fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Disassembles generated code, with a comment before the code of each IR instruction
+pub fn listing(instructions: &[ir::Instruction], assembly: &Assembly) -> String {
+    let code = &assembly.code;
+    let mut listing = String::from("; prologue\n");
+    let mut next = 0;
+    let mut offset = 0;
+
+    while offset < code.len() {
+        while next < assembly.source_map.len() && assembly.source_map[next] <= offset {
+            match instructions.get(next) {
+                Some(instr) => writeln!(listing, "; {}: {:?}", next, instr).unwrap(),
+                None => writeln!(listing, "; epilogue").unwrap(),
+            }
+            next += 1;
+        }
+
+        let (length, text) = match decode(code, offset) {
+            Some(instr) => (instr.length, instr.to_string()),
+            None => (1, String::from("(bad)")),
+        };
+
+        writeln!(
+            listing,
+            "{:08x}  {:<30} {}",
+            offset,
+            hex_bytes(&code[offset..offset + length]),
+            text
+        )
+        .unwrap();
+
+        offset += length;
+    }
+
+    listing
+}
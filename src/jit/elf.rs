@@ -0,0 +1,416 @@
+/// Just enough ELF to turn generated code into something Linux can run or link
+use super::{x86, Symbol, TAPE_SIZE};
+
+const BASE_ADDRESS: u64 = 0x40_0000;
+const ALIGNMENT: u64 = 0x1000;
+
+const ELF_HEADER_SIZE: u16 = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+const PROGRAM_HEADER_COUNT: u16 = 3;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+
+// lea rdi (7 bytes), call (5), mov eax (5), mov edi (5), syscall (2)
+const START_SIZE: u64 = 24;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SECTION_HEADER_SIZE: u16 = 64;
+const SYMBOL_SIZE: u64 = 24;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn elf_header(
+    buffer: &mut Vec<u8>,
+    kind: u16,
+    entry: u64,
+    program_headers: u16,
+    section_headers_offset: u64,
+    section_headers: u16,
+    section_names_index: u16,
+) {
+    // magic, 64 bit, little endian, version 1, System V ABI
+    buffer.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    buffer.extend_from_slice(&[0; 8]);
+
+    push_u16(buffer, kind);
+    push_u16(buffer, EM_X86_64);
+    push_u32(buffer, 1);
+    push_u64(buffer, entry);
+
+    let program_headers_offset = if program_headers > 0 {
+        u64::from(ELF_HEADER_SIZE)
+    } else {
+        0
+    };
+    push_u64(buffer, program_headers_offset);
+    push_u64(buffer, section_headers_offset);
+
+    // flags
+    push_u32(buffer, 0);
+    push_u16(buffer, ELF_HEADER_SIZE);
+    push_u16(buffer, PROGRAM_HEADER_SIZE);
+    push_u16(buffer, program_headers);
+    push_u16(buffer, SECTION_HEADER_SIZE);
+    push_u16(buffer, section_headers);
+    push_u16(buffer, section_names_index);
+}
+
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+fn program_header(buffer: &mut Vec<u8>, segment: &Segment) {
+    push_u32(buffer, segment.kind);
+    push_u32(buffer, segment.flags);
+    push_u64(buffer, segment.offset);
+    push_u64(buffer, segment.address);
+    push_u64(buffer, segment.address);
+    push_u64(buffer, segment.file_size);
+    push_u64(buffer, segment.memory_size);
+    push_u64(buffer, ALIGNMENT);
+}
+
+/// Calls the program with a pointer to the tape and exits with status 0 when it returns
+fn start(tape_offset: u32) -> Vec<u8> {
+    let mut emitter = x86::Emitter::new();
+
+    // lea and call are both relative to the next instruction, we'll put the program right after _start
+    emitter.lea64_rip(x86::Register::Rdi, tape_offset - 7);
+    emitter.callu32(5 + 5 + 2);
+
+    // exit(0)
+    emitter.mov32_imm(x86::Register::Rax, 60);
+    emitter.mov32_imm(x86::Register::Rdi, 0);
+    emitter.syscall();
+
+    let code = emitter.into_inner();
+    debug_assert_eq!(code.len() as u64, START_SIZE);
+
+    code
+}
+
+/// Wraps code generated with `Io::Syscalls` into a static x86_64 Linux executable
+///
+/// The file is mapped read-only and executable at BASE_ADDRESS, and the tape lives in a
+/// zeroed read-write segment on the next page after it
+pub fn executable(code: &[u8]) -> Vec<u8> {
+    let headers_size =
+        u64::from(ELF_HEADER_SIZE) + u64::from(PROGRAM_HEADER_SIZE * PROGRAM_HEADER_COUNT);
+    // _start has a fixed size, so we can figure out where everything goes before generating it
+    let file_size = headers_size + START_SIZE + code.len() as u64;
+
+    let entry = BASE_ADDRESS + headers_size;
+    let tape_address = align_up(BASE_ADDRESS + file_size, ALIGNMENT);
+
+    let start = start((tape_address - entry) as u32);
+
+    let mut buffer = Vec::with_capacity(file_size as usize);
+
+    elf_header(&mut buffer, ET_EXEC, entry, PROGRAM_HEADER_COUNT, 0, 0, 0);
+
+    let segments = [
+        Segment {
+            kind: PT_LOAD,
+            flags: PF_R | PF_X,
+            offset: 0,
+            address: BASE_ADDRESS,
+            file_size,
+            memory_size: file_size,
+        },
+        Segment {
+            kind: PT_LOAD,
+            flags: PF_R | PF_W,
+            offset: 0,
+            address: tape_address,
+            file_size: 0,
+            memory_size: TAPE_SIZE as u64,
+        },
+        // without this the kernel gives us an executable stack
+        Segment {
+            kind: PT_GNU_STACK,
+            flags: PF_R | PF_W,
+            offset: 0,
+            address: 0,
+            file_size: 0,
+            memory_size: 0,
+        },
+    ];
+
+    for segment in &segments {
+        program_header(&mut buffer, segment);
+    }
+
+    buffer.extend_from_slice(&start);
+    buffer.extend_from_slice(code);
+
+    buffer
+}
+
+/// A null-separated table of names, as used by .strtab and .shstrtab
+struct StringTable {
+    contents: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        // index 0 is always the empty string
+        StringTable { contents: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let index = self.contents.len() as u32;
+        self.contents.extend_from_slice(name.as_bytes());
+        self.contents.push(0);
+        index
+    }
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entry_size: u64,
+}
+
+fn section_header(buffer: &mut Vec<u8>, section: &Section) {
+    push_u32(buffer, section.name);
+    push_u32(buffer, section.kind);
+    push_u64(buffer, section.flags);
+    push_u64(buffer, section.address);
+    push_u64(buffer, section.offset);
+    push_u64(buffer, section.size);
+    push_u32(buffer, section.link);
+    push_u32(buffer, section.info);
+    push_u64(buffer, section.alignment);
+    push_u64(buffer, section.entry_size);
+}
+
+fn symbol(buffer: &mut Vec<u8>, name: u32, info: u8, section: u16, value: u64, size: u64) {
+    push_u32(buffer, name);
+    buffer.push(info);
+    // default visibility
+    buffer.push(0);
+    push_u16(buffer, section);
+    push_u64(buffer, value);
+    push_u64(buffer, size);
+}
+
+/// Wraps code generated with `Io::Trampolines` into a relocatable object exporting it as `name`
+///
+/// The code has no relocations, so the object only has .text, a symbol table and an empty
+/// .note.GNU-stack that keeps linkers from asking for an executable stack. From C, the function is
+///
+/// ```c
+/// uint8_t *name(uint8_t *tape,
+///               void (*write)(const uint8_t *buffer, size_t len, void *write_data), void *write_data,
+///               void (*read)(uint8_t *cell, void *read_data), void *read_data);
+/// ```
+///
+/// `tape` is where the data pointer starts, and the function returns where it ended up, so
+/// subtracting the start of the tape gives the index of the cell the program finished on
+pub fn object(code: &[u8], name: &str) -> Vec<u8> {
+    let symbol = Symbol {
+        name: String::from(name),
+        offset: 0,
+        size: code.len(),
+    };
+
+    relocatable(code, 0, &[], &[symbol])
+}
+
+/// Describes code that was loaded at `address` for debuggers
+///
+/// Relocatable files usually have .text at address 0, but GDB takes section addresses as they come
+pub fn symbol_file(code: &[u8], address: u64, symbols: &[Symbol]) -> Vec<u8> {
+    relocatable(code, address, symbols, &[])
+}
+
+fn relocatable(code: &[u8], address: u64, locals: &[Symbol], globals: &[Symbol]) -> Vec<u8> {
+    const TEXT: u16 = 1;
+    const STRTAB: u16 = 4;
+    const SHSTRTAB: u16 = 5;
+    const SECTION_COUNT: u16 = 6;
+
+    let mut section_names = StringTable::new();
+    let text_name = section_names.add(".text");
+    let note_name = section_names.add(".note.GNU-stack");
+    let symtab_name = section_names.add(".symtab");
+    let strtab_name = section_names.add(".strtab");
+    let shstrtab_name = section_names.add(".shstrtab");
+
+    let mut symbol_names = StringTable::new();
+    let mut symbols = Vec::new();
+    symbol(&mut symbols, 0, 0, 0, 0, 0);
+    symbol(&mut symbols, 0, (STB_LOCAL << 4) | STT_SECTION, TEXT, 0, 0);
+
+    // the symbol table has to list local symbols first, and tell us where the global ones start
+    let first_global = 2 + locals.len() as u32;
+    let bindings = locals
+        .iter()
+        .map(|local| (local, STB_LOCAL))
+        .chain(globals.iter().map(|global| (global, STB_GLOBAL)));
+
+    for (function, binding) in bindings {
+        let name = symbol_names.add(&function.name);
+        symbol(
+            &mut symbols,
+            name,
+            (binding << 4) | STT_FUNC,
+            TEXT,
+            function.offset as u64,
+            function.size as u64,
+        );
+    }
+
+    let text_offset = u64::from(ELF_HEADER_SIZE);
+    let symtab_offset = align_up(text_offset + code.len() as u64, 8);
+    let strtab_offset = symtab_offset + symbols.len() as u64;
+    let shstrtab_offset = strtab_offset + symbol_names.contents.len() as u64;
+    let section_headers_offset = align_up(shstrtab_offset + section_names.contents.len() as u64, 8);
+
+    let sections = [
+        Section {
+            name: 0,
+            kind: 0,
+            flags: 0,
+            address: 0,
+            offset: 0,
+            size: 0,
+            link: 0,
+            info: 0,
+            alignment: 0,
+            entry_size: 0,
+        },
+        Section {
+            name: text_name,
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            address,
+            offset: text_offset,
+            size: code.len() as u64,
+            link: 0,
+            info: 0,
+            alignment: 16,
+            entry_size: 0,
+        },
+        Section {
+            name: note_name,
+            kind: SHT_PROGBITS,
+            flags: 0,
+            address: 0,
+            offset: symtab_offset,
+            size: 0,
+            link: 0,
+            info: 0,
+            alignment: 1,
+            entry_size: 0,
+        },
+        Section {
+            name: symtab_name,
+            kind: SHT_SYMTAB,
+            flags: 0,
+            address: 0,
+            offset: symtab_offset,
+            size: symbols.len() as u64,
+            link: u32::from(STRTAB),
+            info: first_global,
+            alignment: 8,
+            entry_size: SYMBOL_SIZE,
+        },
+        Section {
+            name: strtab_name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            address: 0,
+            offset: strtab_offset,
+            size: symbol_names.contents.len() as u64,
+            link: 0,
+            info: 0,
+            alignment: 1,
+            entry_size: 0,
+        },
+        Section {
+            name: shstrtab_name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            address: 0,
+            offset: shstrtab_offset,
+            size: section_names.contents.len() as u64,
+            link: 0,
+            info: 0,
+            alignment: 1,
+            entry_size: 0,
+        },
+    ];
+    debug_assert_eq!(sections.len(), usize::from(SECTION_COUNT));
+
+    let mut buffer = Vec::new();
+
+    elf_header(
+        &mut buffer,
+        ET_REL,
+        0,
+        0,
+        section_headers_offset,
+        SECTION_COUNT,
+        SHSTRTAB,
+    );
+
+    buffer.extend_from_slice(code);
+    buffer.resize(symtab_offset as usize, 0);
+    buffer.extend_from_slice(&symbols);
+    buffer.extend_from_slice(&symbol_names.contents);
+    buffer.extend_from_slice(&section_names.contents);
+    buffer.resize(section_headers_offset as usize, 0);
+
+    for section in &sections {
+        section_header(&mut buffer, section);
+    }
+
+    buffer
+}
@@ -0,0 +1,114 @@
+/// GDB's JIT interface, see https://sourceware.org/gdb/onlinedocs/gdb/JIT-Interface.html
+///
+/// GDB puts a breakpoint on __jit_debug_register_code and walks __jit_debug_descriptor whenever
+/// it's hit, so all we have to do is keep a list of in-memory object files describing our code
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Mutex;
+
+use super::{elf, Symbol};
+
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // GDB only needs this to exist, but an empty function might get merged with some other one
+    compiler_fence(Ordering::SeqCst);
+}
+
+// the descriptor is a global linked list, so only one thread gets to touch it at a time
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Keeps a symbol file registered with GDB for as long as it's alive
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    // the entry points into this, so it must outlive the registration
+    _symfile: Vec<u8>,
+}
+
+impl Registration {
+    pub fn new(address: u64, code: &[u8], symbols: &[Symbol]) -> Self {
+        let symfile = elf::symbol_file(code, address, symbols);
+
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        }));
+
+        let _guard = LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let first = (*descriptor).first_entry;
+
+            (*entry).next_entry = first;
+            if !first.is_null() {
+                (*first).prev_entry = entry;
+            }
+
+            (*descriptor).first_entry = entry;
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+        }
+        __jit_debug_register_code();
+
+        Registration {
+            entry,
+            _symfile: symfile,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _guard = LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = self.entry;
+
+            if (*entry).prev_entry.is_null() {
+                (*descriptor).first_entry = (*entry).next_entry;
+            } else {
+                (*(*entry).prev_entry).next_entry = (*entry).next_entry;
+            }
+
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = (*entry).prev_entry;
+            }
+
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+
+            drop(Box::from_raw(entry));
+        }
+    }
+}
@@ -1,72 +1,137 @@
 /// Toy x86_64 JIT
 use libc;
-use std::alloc::{alloc, dealloc, Layout};
+use snafu::Snafu;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::ffi::c_void;
+use std::io;
 use std::io::{Read, Write};
 use std::mem::transmute;
-use std::ptr::write_bytes;
+use std::ptr;
 use std::slice;
+use std::sync::Arc;
 
+pub mod asm;
+pub mod disasm;
+pub mod elf;
+mod gdb;
+pub mod perf;
 mod x86;
 
 use crate::ir::Instruction;
 
-const PAGE_SIZE: usize = 4096;
+const TAPE_SIZE: usize = 30000;
 
+#[derive(Snafu, Debug)]
+pub enum JitError {
+    #[snafu(display("Failed to map memory for generated code"))]
+    FailedToMap { source: io::Error },
+    #[snafu(display("Failed to change the protection of generated code"))]
+    FailedToProtect { source: io::Error },
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn protect(contents: *mut u8, size: usize, protection: libc::c_int) -> Result<(), JitError> {
+    let result = unsafe { libc::mprotect(contents as *mut libc::c_void, size, protection) };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(JitError::FailedToProtect {
+            source: io::Error::last_os_error(),
+        })
+    }
+}
+
+/// Some pages of memory for generated code, which can't be read, written or executed
+///
+/// Writing to it requires turning it into a SliceableProgram and executing it requires turning it
+/// into a CallableProgram, so the memory is never writable and executable at the same time
 pub struct Program {
     contents: *mut u8,
     size: usize,
+    symbols: Vec<Symbol>,
+    registration: Option<gdb::Registration>,
 }
 
 impl Program {
-    pub fn new(size: usize) -> Self {
-        // allocate some memory to write our instructions
-        let size = size * PAGE_SIZE;
-        let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
-        let contents = unsafe {
-            let raw = alloc(layout);
-            write_bytes(raw, 0xc3, size);
-            libc::mprotect(raw as *mut libc::c_void, size, libc::PROT_NONE);
-            raw
+    /// Maps `size` pages of memory
+    pub fn new(size: usize) -> Result<Self, JitError> {
+        let size = size * page_size();
+        let raw = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if raw == libc::MAP_FAILED {
+            return Err(JitError::FailedToMap {
+                source: io::Error::last_os_error(),
+            });
+        }
+
+        let program = Program {
+            contents: raw as *mut u8,
+            size,
+            symbols: Vec::new(),
+            registration: None,
         };
 
-        Program { contents, size }
+        // anything we jump to by mistake will trap instead of running whatever was there
+        unsafe { ptr::write_bytes(program.contents, 0xcc, size) };
+        protect(program.contents, size, libc::PROT_NONE)?;
+
+        Ok(program)
     }
 
-    pub fn into_sliceable(self) -> SliceableProgram {
+    pub fn into_sliceable(self) -> Result<SliceableProgram, JitError> {
         SliceableProgram::new(self)
     }
 
-    pub fn into_callable(self) -> CallableProgram {
+    pub fn into_callable(self) -> Result<CallableProgram, JitError> {
         CallableProgram::new(self)
     }
 }
 
+// the pointer is owned by the Program, and the only way to mutate what's behind it is through
+// SliceableProgram::as_mut_slice, which takes &mut self
+unsafe impl Send for Program {}
+unsafe impl Sync for Program {}
+
 impl Drop for Program {
     fn drop(&mut self) {
-        let layout = Layout::from_size_align(self.size, PAGE_SIZE).unwrap();
+        // debuggers shouldn't see symbols for code that's gone
+        self.registration.take();
+
+        // munmap doesn't care about protection, so this is fine in every state
         unsafe {
-            dealloc(self.contents, layout);
+            libc::munmap(self.contents as *mut libc::c_void, self.size);
         }
     }
 }
 
+/// A Program that can be read and written, but not executed
 pub struct SliceableProgram {
     program: Program,
 }
 
 impl SliceableProgram {
-    pub fn new(program: Program) -> Self {
-        unsafe {
-            libc::mprotect(
-                program.contents as *mut libc::c_void,
-                program.size,
-                libc::PROT_READ | libc::PROT_WRITE,
-            );
-        }
-        SliceableProgram { program }
+    pub fn new(program: Program) -> Result<Self, JitError> {
+        protect(
+            program.contents,
+            program.size,
+            libc::PROT_READ | libc::PROT_WRITE,
+        )?;
+
+        Ok(SliceableProgram { program })
     }
 
     pub fn as_slice(&self) -> &[u8] {
@@ -77,50 +142,133 @@ impl SliceableProgram {
         unsafe { slice::from_raw_parts_mut(self.program.contents, self.program.size) }
     }
 
-    pub fn lock(self) -> Program {
-        unsafe {
-            libc::mprotect(
-                self.program.contents as *mut libc::c_void,
-                self.program.size,
-                libc::PROT_NONE,
-            );
-        }
-        self.program
+    pub fn lock(self) -> Result<Program, JitError> {
+        protect(self.program.contents, self.program.size, libc::PROT_NONE)?;
+
+        Ok(self.program)
     }
 }
 
+/// A Program that can be read and executed, but not written
+///
+/// Generated code keeps all of its state in registers, on its stack and in the cells it's given, so
+/// the same CallableProgram can run any number of times, from any number of threads at once
 pub struct CallableProgram {
     program: Program,
 }
 
 impl CallableProgram {
-    pub fn new(program: Program) -> Self {
-        unsafe {
-            libc::mprotect(
-                program.contents as *mut libc::c_void,
-                program.size,
-                libc::PROT_READ | libc::PROT_EXEC,
-            );
-        }
-
-        CallableProgram { program }
+    pub fn new(program: Program) -> Result<Self, JitError> {
+        protect(
+            program.contents,
+            program.size,
+            libc::PROT_READ | libc::PROT_EXEC,
+        )?;
+
+        Ok(CallableProgram { program })
     }
 
     pub fn as_function(
-        &mut self,
+        &self,
     ) -> unsafe extern "C" fn(
         *mut u8,
         *mut c_void,
         *mut WriteWrapper,
         *mut c_void,
         *mut ReadWrapper,
-    ) -> i32 {
+    ) -> *mut u8 {
         unsafe { transmute(self.program.contents) }
     }
 
-    pub fn lock(self) -> Program {
-        self.program
+    /// Runs the code with the data pointer at `tape[data_pointer]`, and returns where the data pointer ended up
+    pub fn run(
+        &self,
+        tape: &mut [u8],
+        data_pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> usize {
+        let program = self.as_function();
+
+        let mut out_wrapper = WriteWrapper {
+            write: output as *mut dyn Write,
+        };
+
+        let mut in_wrapper = ReadWrapper {
+            read: input as *mut dyn Read,
+        };
+
+        let start = tape.as_mut_ptr();
+
+        let end = unsafe {
+            program(
+                start.add(data_pointer),
+                write_trampoline as *mut c_void,
+                &mut out_wrapper as *mut WriteWrapper,
+                read_trampoline as *mut c_void,
+                &mut in_wrapper as *mut ReadWrapper,
+            )
+        };
+
+        end as usize - start as usize
     }
+
+    /// Runs code from transform_guarded with the data pointer at `tape[data_pointer]`, and returns
+    /// where the data pointer ended up along with the index of the instruction that would have
+    /// moved it off the tape, or the number of instructions if it ran to the end
+    pub fn run_guarded(
+        &self,
+        tape: &mut [u8],
+        data_pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> (usize, usize) {
+        let program: unsafe extern "C" fn(
+            *mut u8,
+            *mut c_void,
+            *mut WriteWrapper,
+            *mut c_void,
+            *mut ReadWrapper,
+            *mut u8,
+        ) -> GuardedExit = unsafe { transmute(self.program.contents) };
+
+        let mut out_wrapper = WriteWrapper {
+            write: output as *mut dyn Write,
+        };
+
+        let mut in_wrapper = ReadWrapper {
+            read: input as *mut dyn Read,
+        };
+
+        assert!(data_pointer < tape.len(), "data pointer out of bounds!");
+        let start = tape.as_mut_ptr();
+
+        let exit = unsafe {
+            program(
+                start.add(data_pointer),
+                write_trampoline as *mut c_void,
+                &mut out_wrapper as *mut WriteWrapper,
+                read_trampoline as *mut c_void,
+                &mut in_wrapper as *mut ReadWrapper,
+                start,
+            )
+        };
+
+        (exit.data_pointer as usize - start as usize, exit.stopped_at)
+    }
+
+    pub fn lock(self) -> Result<Program, JitError> {
+        protect(self.program.contents, self.program.size, libc::PROT_NONE)?;
+
+        Ok(self.program)
+    }
+}
+
+/// What code from assemble_guarded returns, in rax and rdx
+#[repr(C)]
+pub struct GuardedExit {
+    data_pointer: *mut u8,
+    stopped_at: usize,
 }
 
 #[derive(Debug)]
@@ -129,20 +277,233 @@ struct JumpInfo {
     target: usize,
 }
 
-pub fn transform(instructions: &[Instruction]) -> Program {
+// the current cell is kept in al across straight-line code
+const CELL_REGISTER: x86::Register = x86::Register::Rax;
+
+/// Where the value of the current cell lives while we're emitting code
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CellCache {
+    /// CELL_REGISTER holds garbage, the cell only lives in memory
+    Empty,
+    /// CELL_REGISTER and the cell in memory hold the same value
+    Clean,
+    /// CELL_REGISTER holds a value that hasn't been written back to memory yet
+    Dirty,
+}
+
+impl CellCache {
+    fn load(&mut self, emitter: &mut x86::Emitter) {
+        if *self == CellCache::Empty {
+            emitter.movzx8_reg_ptr(CELL_REGISTER, x86::Register::Rdi);
+            *self = CellCache::Clean;
+        }
+    }
+
+    fn spill(&mut self, emitter: &mut x86::Emitter) {
+        if *self == CellCache::Dirty {
+            emitter.mov8_ptr_reg(x86::Register::Rdi, CELL_REGISTER);
+            *self = CellCache::Clean;
+        }
+    }
+
+    fn increment(&mut self, emitter: &mut x86::Emitter, inc: i32) {
+        if inc == 0 {
+            return;
+        }
+
+        self.load(emitter);
+
+        if inc.is_positive() {
+            emitter.addu8_reg8(CELL_REGISTER, inc as u8);
+        } else {
+            emitter.subu8_reg8(CELL_REGISTER, -inc as u8);
+        }
+
+        *self = CellCache::Dirty;
+    }
+
+    /// Writes the cell back (if needed) and forgets about it, for when rdi moves or CELL_REGISTER gets clobbered
+    fn invalidate(&mut self, emitter: &mut x86::Emitter) {
+        self.spill(emitter);
+        *self = CellCache::Empty;
+    }
+}
+
+/// How the generated code talks to the outside world
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Io {
+    /// Calls the output and input functions it receives as parameters, which is what Vm does
+    ///
+    /// The generated function is
+    /// `extern "C" fn(*mut u8, *mut c_void, *mut WriteWrapper, *mut c_void, *mut ReadWrapper) -> *mut u8`
+    Trampolines,
+    /// Does raw Linux read and write syscalls on stdin and stdout, so it can run without bfr around
+    ///
+    /// The generated function is `extern "C" fn(*mut u8) -> *mut u8`
+    Syscalls,
+}
+
+fn increment_register(emitter: &mut x86::Emitter, register: x86::Register, inc: i32) {
+    // the 8 bit immediates are sign extended, so they only cover -128..=127
+    if (1..=127).contains(&inc) {
+        emitter.addu8_reg(register, inc as u8);
+    } else if (-127..0).contains(&inc) {
+        emitter.subu8_reg(register, -inc as u8);
+    } else if inc.is_positive() {
+        emitter.addu32_reg(register, inc as u32);
+    } else if inc.is_negative() {
+        emitter.subu32_reg(register, -inc as u32);
+    }
+}
+
+fn increment_pointer(emitter: &mut x86::Emitter, inc: i32) {
+    increment_register(emitter, x86::Register::Rdi, inc);
+}
+
+/// Leaves the function if moving the data pointer by `inc` would take it off the tape, with the
+/// index of the instruction in rbx. Returns where the jump out is, to be patched once the
+/// epilogue exists
+///
+/// The start of the tape lives at [rbx], just past the output buffer
+fn check_pointer(emitter: &mut x86::Emitter, inc: i32, tape_size: u32, idx: usize) -> usize {
+    // r10: where the data pointer would end up, counting from the start of the tape
+    emitter.mov64_reg_ptr(x86::Register::R11, x86::Register::Rbx);
+    emitter.mov64_reg(x86::Register::R10, x86::Register::Rdi);
+    emitter.sub64_reg(x86::Register::R10, x86::Register::R11);
+    increment_register(emitter, x86::Register::R10, inc);
+
+    // anything before the start wraps around to a huge number, so one comparison covers both ends
+    emitter.cmpu32_reg(x86::Register::R10, tape_size);
+    let skip = emitter.index;
+    emitter.jbu8(0);
+
+    emitter.mov32_imm(x86::Register::Rbx, idx as u32);
+    let bail = emitter.index;
+    emitter.jmpu32(0);
+
+    emitter.patch_jumpu8(skip);
+
+    bail
+}
+
+// output is appended to a buffer on the generated function's stack and only handed to the
+// output function when it fills up, before reading input and when the program ends
+const OUTPUT_BUFFER_SIZE: u32 = 4096;
+
+/// Hands the buffered output to the output function and rewinds the buffer
+///
+/// Clobbers CELL_REGISTER, but reloads it from memory so the cell cache stays clean
+fn flush_output(emitter: &mut x86::Emitter, io: Io) {
+    // rsi: length of the buffered output
+    emitter.mov64_reg(x86::Register::Rsi, x86::Register::R15);
+    emitter.sub64_reg(x86::Register::Rsi, x86::Register::Rsp);
+
+    emitter.push(x86::Register::Rdi);
+
+    match io {
+        Io::Trampolines => {
+            // rdi (1st parameter): start of the buffer, which is cursor - length as we just moved rsp
+            emitter.mov64_reg(x86::Register::Rdi, x86::Register::R15);
+            emitter.sub64_reg(x86::Register::Rdi, x86::Register::Rsi);
+            // rdx (3rd parameter): pointer to WriteWrapper
+            emitter.mov64_reg(x86::Register::Rdx, x86::Register::R12);
+            emitter.call64(x86::Register::Rbp);
+        }
+        Io::Syscalls => {
+            // write(1, cursor - length, length)
+            emitter.mov64_reg(x86::Register::Rdx, x86::Register::Rsi);
+            emitter.mov64_reg(x86::Register::Rsi, x86::Register::R15);
+            emitter.sub64_reg(x86::Register::Rsi, x86::Register::Rdx);
+            emitter.mov32_imm(x86::Register::Rax, 1);
+            emitter.mov32_imm(x86::Register::Rdi, 1);
+            emitter.syscall();
+        }
+    }
+
+    emitter.pop(x86::Register::Rdi);
+
+    emitter.mov64_reg(x86::Register::R15, x86::Register::Rsp);
+    emitter.movzx8_reg_ptr(CELL_REGISTER, x86::Register::Rdi);
+}
+
+/// Flushes the output buffer if there's anything in it
+fn flush_pending_output(emitter: &mut x86::Emitter, io: Io) {
+    emitter.cmp64_reg(x86::Register::R15, x86::Register::Rsp);
+    let skip = emitter.index;
+    emitter.jeu8(0);
+    flush_output(emitter, io);
+    emitter.patch_jumpu8(skip);
+}
+
+/// Reads a byte into the current cell
+///
+/// Clobbers CELL_REGISTER
+fn read_byte(emitter: &mut x86::Emitter, io: Io) {
+    match io {
+        Io::Trampolines => {
+            // move ptr to ReadWrapper to Rsi
+            emitter.mov64_reg(x86::Register::Rsi, x86::Register::R14);
+
+            emitter.push(x86::Register::Rdi);
+            emitter.call64(x86::Register::R13);
+            emitter.pop(x86::Register::Rdi);
+        }
+        Io::Syscalls => {
+            // read(0, cell, 1), which leaves the cell alone on EOF
+            emitter.push(x86::Register::Rdi);
+            emitter.mov64_reg(x86::Register::Rsi, x86::Register::Rdi);
+            emitter.mov32_imm(x86::Register::Rax, 0);
+            emitter.mov32_imm(x86::Register::Rdi, 0);
+            emitter.mov32_imm(x86::Register::Rdx, 1);
+            emitter.syscall();
+            emitter.pop(x86::Register::Rdi);
+        }
+    }
+}
+
+/// Machine code for a program
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub code: Vec<u8>,
+    /// Where the code for each instruction starts, plus where the epilogue starts
+    pub source_map: Vec<usize>,
+}
+
+/// Generates machine code for a function that runs the whole program
+///
+/// The code doesn't reference anything outside of itself and can be loaded anywhere
+pub fn assemble(instructions: &[Instruction], io: Io) -> Assembly {
+    assemble_with(instructions, io, None)
+}
+
+/// Like assemble with Io::Trampolines, but the code checks every move of the data pointer, and
+/// returns before one would leave a tape of `tape_size` cells
+///
+/// The generated function is
+/// `extern "C" fn(*mut u8, *mut c_void, *mut WriteWrapper, *mut c_void, *mut ReadWrapper, *mut u8) -> GuardedExit`,
+/// where the last parameter is the start of the tape
+pub fn assemble_guarded(instructions: &[Instruction], tape_size: usize) -> Assembly {
+    let tape_size = u32::try_from(tape_size).expect("tape doesn't fit in an immediate");
+
+    assemble_with(instructions, Io::Trampolines, Some(tape_size))
+}
+
+fn assemble_with(instructions: &[Instruction], io: Io, guard: Option<u32>) -> Assembly {
     // we'll emit something that respects x86_64 system-v:
-    // rdi (1st parameter): pointer to cell array
+    // rdi (1st parameter): pointer to the current cell
+    // with Io::Trampolines we also get
     // rsi (2nd parameter): pointer to output function
     // rdx (3rd parameter): pointer to WriteWrapper
     // rcx (4th parameter): pointer to input function
     // r8  (5th parameter): pointer to ReadWrapper
-    let program = Program::new(8);
-    let mut sliceable = program.into_sliceable();
-
-    let slice = sliceable.as_mut_slice();
-    let mut emitter = x86::Emitter::new(slice);
+    //
+    // the output buffer lives at [rsp, rsp + OUTPUT_BUFFER_SIZE)
+    // r15 points to where the next byte goes and rbx to the end of the buffer
+    // with a guard, r9 (6th parameter) is the start of the tape, which is kept at [rbx]
+    let mut emitter = x86::Emitter::new();
     // we receive a stack that's misaligned by 8 bytes at the start of the function
     // we always push on argument onto it and that aligns it :)
+    // (we push six registers and reserve the buffer, which doesn't change that)
 
     // move arguments to saved registers
     // rsi -> rbp
@@ -151,57 +512,64 @@ pub fn transform(instructions: &[Instruction]) -> Program {
     // r8 -> r14
 
     emitter.push(x86::Register::Rbp);
+    emitter.push(x86::Register::Rbx);
     emitter.push(x86::Register::R12);
     emitter.push(x86::Register::R13);
     emitter.push(x86::Register::R14);
+    emitter.push(x86::Register::R15);
 
-    emitter.mov64_reg(x86::Register::Rbp, x86::Register::Rsi);
-    emitter.mov64_reg(x86::Register::R12, x86::Register::Rdx);
-    emitter.mov64_reg(x86::Register::R13, x86::Register::Rcx);
-    emitter.mov64_reg(x86::Register::R14, x86::Register::R8);
+    if io == Io::Trampolines {
+        emitter.mov64_reg(x86::Register::Rbp, x86::Register::Rsi);
+        emitter.mov64_reg(x86::Register::R12, x86::Register::Rdx);
+        emitter.mov64_reg(x86::Register::R13, x86::Register::Rcx);
+        emitter.mov64_reg(x86::Register::R14, x86::Register::R8);
+    }
+
+    if guard.is_some() {
+        // twice, so the stack stays aligned
+        emitter.push(x86::Register::R9);
+        emitter.push(x86::Register::R9);
+    }
+
+    emitter.subu32_reg(x86::Register::Rsp, OUTPUT_BUFFER_SIZE);
+    emitter.mov64_reg(x86::Register::R15, x86::Register::Rsp);
+    emitter.mov64_reg(x86::Register::Rbx, x86::Register::Rsp);
+    emitter.addu32_reg(x86::Register::Rbx, OUTPUT_BUFFER_SIZE);
 
     let mut jumps = BTreeMap::new();
+    let mut cache = CellCache::Empty;
+    let mut source_map = Vec::with_capacity(instructions.len() + 1);
+    let mut bails = Vec::new();
 
     for (idx, instr) in instructions.iter().enumerate() {
+        source_map.push(emitter.index);
+
         match instr {
             Instruction::IncrementPointer(inc) => {
-                if inc.is_positive() {
-                    emitter.addu8_reg(x86::Register::Rdi, *inc as u8);
-                } else if inc.is_negative() {
-                    emitter.subu8_reg(x86::Register::Rdi, -*inc as u8);
+                cache.invalidate(&mut emitter);
+                if let Some(tape_size) = guard {
+                    bails.push(check_pointer(&mut emitter, *inc, tape_size, idx));
                 }
+                increment_pointer(&mut emitter, *inc);
             }
             Instruction::IncrementByte(inc) => {
-                if inc.is_positive() {
-                    emitter.addu8_ptr(x86::Register::Rdi, *inc as u8);
-                } else if inc.is_negative() {
-                    emitter.subu8_ptr(x86::Register::Rdi, -*inc as u8);
-                }
+                cache.increment(&mut emitter, *inc);
             }
             Instruction::IncrementPointerAndByte(pointer_inc, byte_inc) => {
-                if byte_inc.is_positive() {
-                    emitter.addu8_ptr_u8disp(
-                        x86::Register::Rdi,
-                        *pointer_inc as u8,
-                        *byte_inc as u8,
-                    );
-                } else if byte_inc.is_negative() {
-                    emitter.subu8_ptr_u8disp(
-                        x86::Register::Rdi,
-                        *pointer_inc as u8,
-                        -*byte_inc as u8,
-                    );
-                }
-
-                if pointer_inc.is_positive() {
-                    emitter.addu8_reg(x86::Register::Rdi, *pointer_inc as u8);
-                } else if pointer_inc.is_negative() {
-                    emitter.subu8_reg(x86::Register::Rdi, -*pointer_inc as u8);
+                cache.invalidate(&mut emitter);
+                if let Some(tape_size) = guard {
+                    bails.push(check_pointer(&mut emitter, *pointer_inc, tape_size, idx));
                 }
+                increment_pointer(&mut emitter, *pointer_inc);
+                cache.increment(&mut emitter, *byte_inc);
             }
             // The way I've implemented jumps is terribly hacky. I should probably find a better solution someday
+            // Both ends of a loop leave the cell loaded and written back, so whichever way we got to the
+            // instruction after a jump, CELL_REGISTER is clean
             Instruction::JumpBackwardsIfNotZero(jmp) => {
-                emitter.cmpu8_ptr(x86::Register::Rdi, 0);
+                cache.load(&mut emitter);
+                cache.spill(&mut emitter);
+                emitter.test8_reg(CELL_REGISTER);
 
                 let jumpinfo = JumpInfo {
                     target: idx - jmp,
@@ -213,7 +581,9 @@ pub fn transform(instructions: &[Instruction]) -> Program {
                 emitter.jneu32(42);
             }
             Instruction::JumpForwardsIfZero(jmp) => {
-                emitter.cmpu8_ptr(x86::Register::Rdi, 0);
+                cache.load(&mut emitter);
+                cache.spill(&mut emitter);
+                emitter.test8_reg(CELL_REGISTER);
 
                 let jumpinfo = JumpInfo {
                     target: idx + jmp,
@@ -225,28 +595,65 @@ pub fn transform(instructions: &[Instruction]) -> Program {
                 emitter.jeu32(42);
             }
             Instruction::OutputByte => {
-                // move ptr to WriteWrapper to Rsi
-                emitter.mov64_reg(x86::Register::Rsi, x86::Register::R12);
-
-                emitter.push(x86::Register::Rdi);
-                emitter.call64(x86::Register::Rbp);
-                emitter.pop(x86::Register::Rdi);
+                // flushing reloads the cell from memory, so it must be up to date
+                cache.load(&mut emitter);
+                cache.spill(&mut emitter);
+
+                emitter.mov8_ptr_reg(x86::Register::R15, CELL_REGISTER);
+                emitter.addu8_reg(x86::Register::R15, 1);
+                emitter.cmp64_reg(x86::Register::R15, x86::Register::Rbx);
+
+                let skip = emitter.index;
+                emitter.jneu8(0);
+                flush_output(&mut emitter, io);
+                emitter.patch_jumpu8(skip);
             }
             Instruction::ReadByte => {
-                // move ptr to ReadWrapper to Rsi
-                emitter.mov64_reg(x86::Register::Rsi, x86::Register::R14);
-
-                emitter.push(x86::Register::Rdi);
-                emitter.call64(x86::Register::R13);
-                emitter.pop(x86::Register::Rdi);
+                cache.invalidate(&mut emitter);
+                flush_pending_output(&mut emitter, io);
+                read_byte(&mut emitter, io);
             }
         }
     }
 
+    source_map.push(emitter.index);
+
+    cache.spill(&mut emitter);
+
+    if guard.is_some() {
+        // ran to the end, so it stopped past the last instruction
+        emitter.mov32_imm(x86::Register::Rbx, instructions.len() as u32);
+    }
+
+    // checks that fail land here, after the cell was already written back
+    let epilogue = emitter.index;
+    flush_pending_output(&mut emitter, io);
+
+    let reserved = if guard.is_some() { 16 } else { 0 };
+    emitter.addu32_reg(x86::Register::Rsp, OUTPUT_BUFFER_SIZE + reserved);
+
+    // we return the data pointer, so code for parts of a program can hand control back to an interpreter
+    emitter.mov64_reg(x86::Register::Rax, x86::Register::Rdi);
+
+    if guard.is_some() {
+        // and where it stopped, as the second half of GuardedExit
+        emitter.mov64_reg(x86::Register::Rdx, x86::Register::Rbx);
+    }
+
+    emitter.pop(x86::Register::R15);
     emitter.pop(x86::Register::R14);
     emitter.pop(x86::Register::R13);
     emitter.pop(x86::Register::R12);
+    emitter.pop(x86::Register::Rbx);
     emitter.pop(x86::Register::Rbp);
+    emitter.ret();
+
+    let mut code = emitter.into_inner();
+
+    for bail in bails {
+        let offset = epilogue as isize - (bail as isize + 5);
+        code[bail + 1..bail + 5].copy_from_slice(&(offset as i32).to_le_bytes());
+    }
 
     for jumpinfo in jumps.values() {
         let target = jumps.get(&jumpinfo.target).unwrap();
@@ -261,70 +668,191 @@ pub fn transform(instructions: &[Instruction]) -> Program {
         let le_bytes = i32::try_from(offset)
             .expect("offset overflowed i32")
             .to_le_bytes();
-        slice[jumpinfo.asm_offset + 2] = le_bytes[0];
-        slice[jumpinfo.asm_offset + 3] = le_bytes[1];
-        slice[jumpinfo.asm_offset + 4] = le_bytes[2];
-        slice[jumpinfo.asm_offset + 5] = le_bytes[3];
+        code[jumpinfo.asm_offset + 2] = le_bytes[0];
+        code[jumpinfo.asm_offset + 3] = le_bytes[1];
+        code[jumpinfo.asm_offset + 4] = le_bytes[2];
+        code[jumpinfo.asm_offset + 5] = le_bytes[3];
     }
 
-    sliceable.lock()
+    Assembly { code, source_map }
+}
+
+/// A named range of generated code
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
 }
 
-unsafe extern "C" fn write_trampoline(byte_ptr: *mut u8, wrapper_ptr: *mut WriteWrapper) {
+/// Splits generated code into one symbol per loop, so profilers can tell loops apart
+///
+/// Loops are named after the index of their JumpForwardsIfZero. Code in a loop but outside of its
+/// inner loops belongs to the loop, everything outside of loops belongs to `bf_main`. Symbols never
+/// overlap, so a loop with inner loops may get several symbols with the same name
+pub fn symbols(instructions: &[Instruction], assembly: &Assembly) -> Vec<Symbol> {
+    fn close(symbols: &mut Vec<Symbol>, owner: Option<&usize>, start: usize, end: usize) {
+        if end > start {
+            let name = match owner {
+                Some(idx) => format!("bf_loop_{}", idx),
+                None => String::from("bf_main"),
+            };
+
+            symbols.push(Symbol {
+                name,
+                offset: start,
+                size: end - start,
+            });
+        }
+    }
+
+    let mut symbols = Vec::new();
+    let mut loops = Vec::new();
+    let mut start = 0;
+
+    for (idx, instr) in instructions.iter().enumerate() {
+        match instr {
+            Instruction::JumpForwardsIfZero(_) => {
+                let end = assembly.source_map[idx];
+                close(&mut symbols, loops.last(), start, end);
+
+                loops.push(idx);
+                start = end;
+            }
+            Instruction::JumpBackwardsIfNotZero(_) => {
+                let end = assembly.source_map[idx + 1];
+                close(&mut symbols, loops.last(), start, end);
+
+                loops.pop();
+                start = end;
+            }
+            _ => (),
+        }
+    }
+
+    close(&mut symbols, None, start, assembly.code.len());
+
+    symbols
+}
+
+pub fn transform(instructions: &[Instruction]) -> Result<Program, JitError> {
+    load(instructions, &assemble(instructions, Io::Trampolines))
+}
+
+/// Like transform, but the code stops before the data pointer leaves a tape of `tape_size` cells,
+/// and has to be run with CallableProgram::run_guarded
+pub fn transform_guarded(
+    instructions: &[Instruction],
+    tape_size: usize,
+) -> Result<Program, JitError> {
+    load(instructions, &assemble_guarded(instructions, tape_size))
+}
+
+/// Loads code assembled with Io::Trampolines for `instructions`, which may come from an earlier run
+pub fn load(instructions: &[Instruction], assembly: &Assembly) -> Result<Program, JitError> {
+    let code = &assembly.code;
+
+    let program = Program::new(code.len() / page_size() + 1)?;
+    let mut sliceable = program.into_sliceable()?;
+    sliceable.as_mut_slice()[..code.len()].copy_from_slice(code);
+
+    let mut program = sliceable.lock()?;
+    program.symbols = symbols(instructions, assembly);
+    program.registration = Some(gdb::Registration::new(
+        program.contents as u64,
+        code,
+        &program.symbols,
+    ));
+
+    Ok(program)
+}
+
+unsafe extern "C" fn write_trampoline(
+    buffer: *const u8,
+    len: usize,
+    wrapper_ptr: *mut WriteWrapper,
+) {
     let wrapper = &*wrapper_ptr;
     let output = &mut *wrapper.write;
-    let byte = *byte_ptr;
-    output.write_all(&[byte]).unwrap();
+    let slice = slice::from_raw_parts(buffer, len);
+    output.write_all(slice).unwrap();
 }
 
 unsafe extern "C" fn read_trampoline(byte_ptr: *mut u8, wrapper_ptr: *mut ReadWrapper) {
     let wrapper = &*wrapper_ptr;
     let input = &mut *wrapper.read;
     let slice = slice::from_raw_parts_mut(byte_ptr, 1);
-    input.read_exact(slice).unwrap();
+    // like the interpreters, the end of input leaves the cell alone
+    match input.read_exact(slice) {
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => panic!("{}", err),
+        _ => (),
+    }
 }
 
 // I thought about a Wrapper<T>, but I'm not going to muck aroung with generics here
-pub struct WriteWrapper {
-    write: *mut dyn Write,
+pub struct WriteWrapper<'a> {
+    write: *mut (dyn Write + 'a),
 }
 
-pub struct ReadWrapper {
-    read: *mut dyn Read,
+pub struct ReadWrapper<'a> {
+    read: *mut (dyn Read + 'a),
 }
 
+// servers compile once and run requests on whatever thread they land on
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CallableProgram>();
+};
+
+/// The state of a single run of a compiled program
+///
+/// The program itself is shared, so any number of Vms can run it at the same time
 pub struct Vm {
-    program: CallableProgram,
-    cells: [u8; 30000],
+    program: Arc<CallableProgram>,
+    cells: [u8; TAPE_SIZE],
 }
 
 impl Vm {
-    pub fn new(program: Program) -> Self {
+    pub fn new(program: Program) -> Result<Self, JitError> {
+        Ok(Vm::with_shared(Arc::new(program.into_callable()?)))
+    }
+
+    /// Creates a vm for a program that's already callable, and maybe being run by other vms
+    pub fn with_shared(program: Arc<CallableProgram>) -> Self {
         Vm {
-            program: program.into_callable(),
-            cells: [0; 30000],
+            program,
+            cells: [0; TAPE_SIZE],
         }
     }
 
+    pub fn program(&self) -> &Arc<CallableProgram> {
+        &self.program
+    }
+
+    /// Zeroes the cells, so the program can be run again from scratch
+    pub fn reset(&mut self) {
+        self.cells = [0; TAPE_SIZE];
+    }
+
     pub fn vm_loop(&mut self, input: &mut dyn Read, output: &mut dyn Write) {
-        let program = self.program.as_function();
+        self.program.run(&mut self.cells, 0, input, output);
+    }
+}
 
-        let mut out_wrapper = WriteWrapper {
-            write: output as *const dyn Write as *mut dyn Write,
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brainfuck, ir};
 
-        let mut in_wrapper = ReadWrapper {
-            read: input as *const dyn Read as *mut dyn Read,
-        };
+    #[test]
+    fn reads_past_cell_0_and_leaves_the_cell_alone_at_the_end_of_input() {
+        let instructions = ir::transform(&brainfuck::parse(b">,.>+,.,.".iter().copied())).unwrap();
+        let mut output = Vec::new();
 
-        unsafe {
-            program(
-                self.cells.as_mut_ptr() as *mut u8,
-                write_trampoline as *mut c_void,
-                &mut out_wrapper as *mut WriteWrapper,
-                read_trampoline as *mut c_void,
-                &mut in_wrapper as *mut ReadWrapper,
-            )
-        };
+        Vm::new(transform(&instructions).unwrap())
+            .unwrap()
+            .vm_loop(&mut &b"a"[..], &mut output);
+
+        assert_eq!(output, [b'a', 1, 1]);
     }
 }
@@ -0,0 +1,31 @@
+/// Tells perf about generated code through /tmp/perf-<pid>.map
+///
+/// See tools/perf/Documentation/jit-interface.txt in the Linux tree for the format
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::process;
+
+use super::Program;
+
+/// Appends an entry for each of the program's symbols to this process' perf map
+pub fn write_map(program: &Program) -> io::Result<()> {
+    let base = program.contents as usize;
+    let mut map = String::new();
+
+    for symbol in &program.symbols {
+        writeln!(
+            map,
+            "{:x} {:x} {}",
+            base + symbol.offset,
+            symbol.size,
+            symbol.name
+        )
+        .unwrap();
+    }
+
+    let path = format!("/tmp/perf-{}.map", process::id());
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(map.as_bytes())
+}
@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 // Sincerely, fuck this ISA
 #[allow(dead_code)]
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
@@ -21,19 +23,26 @@ pub enum Register {
     R15 = 15,
 }
 
-pub struct Emitter<'a> {
+pub struct Emitter {
     pub index: usize,
-    buffer: &'a mut [u8],
+    buffer: Vec<u8>,
 }
 
-impl<'a> Emitter<'a> {
-    pub fn new(buffer: &'a mut [u8]) -> Self {
-        Emitter { index: 0, buffer }
+impl Emitter {
+    pub fn new() -> Self {
+        Emitter {
+            index: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer
     }
 
     #[inline(always)]
     pub fn emit(&mut self, emitted: &[u8]) {
-        self.buffer[self.index..self.index + emitted.len()].copy_from_slice(emitted);
+        self.buffer.extend_from_slice(emitted);
         self.index += emitted.len();
     }
 
@@ -66,8 +75,12 @@ impl<'a> Emitter<'a> {
         rexw
     }
 
+    fn rexw_rm(&self, rm: Register) -> u8 {
+        self.rexw_r_rm(Register::Rax, rm)
+    }
+
     fn rex_rm(&self, register: Register) -> Option<u8> {
-        if register > Register::R8 {
+        if register >= Register::R8 {
             Some(0b0100_0001)
         } else {
             None
@@ -76,7 +89,7 @@ impl<'a> Emitter<'a> {
 
     pub fn addu8_reg(&mut self, register: Register, imm: u8) {
         let op = [
-            self.rexw_r(register),
+            self.rexw_rm(register),
             0x83,
             self.modrm(0b11, 0, register as u8),
             imm,
@@ -87,7 +100,7 @@ impl<'a> Emitter<'a> {
 
     pub fn subu8_reg(&mut self, register: Register, imm: u8) {
         let op = [
-            self.rexw_r(register),
+            self.rexw_rm(register),
             0x83,
             self.modrm(0b11, 5, register as u8),
             imm,
@@ -96,36 +109,130 @@ impl<'a> Emitter<'a> {
         self.emit(&op);
     }
 
-    pub fn addu8_ptr(&mut self, register: Register, imm: u8) {
-        let op = [0x80, self.modrm(0b00, 0, register as u8), imm];
+    // The byte register variants below don't emit a REX prefix, so only al, cl, dl and bl can be used
+
+    pub fn addu8_reg8(&mut self, register: Register, imm: u8) {
+        let op = [0x80, self.modrm(0b11, 0, register as u8), imm];
+
+        self.emit(&op);
+    }
+
+    pub fn subu8_reg8(&mut self, register: Register, imm: u8) {
+        let op = [0x80, self.modrm(0b11, 5, register as u8), imm];
 
         self.emit(&op);
     }
 
-    pub fn subu8_ptr(&mut self, register: Register, imm: u8) {
-        let op = [0x80, self.modrm(0b00, 5, register as u8), imm];
+    // zero extends into the whole register, which avoids a false dependency on its previous value
+    pub fn movzx8_reg_ptr(&mut self, dst: Register, src: Register) {
+        let op = [0x0f, 0xb6, self.modrm(0b00, dst as u8, src as u8)];
 
         self.emit(&op);
     }
 
-    pub fn addu8_ptr_u8disp(&mut self, register: Register, disp: u8, imm: u8) {
-        let op = [0x80, self.modrm(0b01, 0, register as u8), disp, imm];
+    pub fn mov8_ptr_reg(&mut self, dst: Register, src: Register) {
+        if let Some(rexrm) = self.rex_rm(dst) {
+            let op = [rexrm, 0x88, self.modrm(0b00, src as u8, dst as u8)];
+            self.emit(&op);
+        } else {
+            let op = [0x88, self.modrm(0b00, src as u8, dst as u8)];
+            self.emit(&op);
+        }
+    }
+
+    pub fn test8_reg(&mut self, register: Register) {
+        let op = [0x84, self.modrm(0b11, register as u8, register as u8)];
 
         self.emit(&op);
     }
 
-    pub fn subu8_ptr_u8disp(&mut self, register: Register, disp: u8, imm: u8) {
-        let op = [0x80, self.modrm(0b01, 5, register as u8), disp, imm];
+    pub fn addu32_reg(&mut self, register: Register, imm: u32) {
+        let mut op = [
+            self.rexw_rm(register),
+            0x81,
+            self.modrm(0b11, 0, register as u8),
+            0,
+            0,
+            0,
+            0,
+        ];
+
+        op[3..].copy_from_slice(&imm.to_le_bytes());
 
         self.emit(&op);
     }
 
-    pub fn cmpu8_ptr(&mut self, register: Register, imm: u8) {
-        let op = [0x80, self.modrm(0b00, 7, register as u8), imm];
+    pub fn subu32_reg(&mut self, register: Register, imm: u32) {
+        let mut op = [
+            self.rexw_rm(register),
+            0x81,
+            self.modrm(0b11, 5, register as u8),
+            0,
+            0,
+            0,
+            0,
+        ];
+
+        op[3..].copy_from_slice(&imm.to_le_bytes());
+
+        self.emit(&op);
+    }
+
+    pub fn sub64_reg(&mut self, dst: Register, src: Register) {
+        let op = [
+            self.rexw_r_rm(src, dst),
+            0x29,
+            self.modrm(0b11, src as u8, dst as u8),
+        ];
+
+        self.emit(&op);
+    }
+
+    pub fn cmp64_reg(&mut self, left: Register, right: Register) {
+        let op = [
+            self.rexw_r_rm(right, left),
+            0x39,
+            self.modrm(0b11, right as u8, left as u8),
+        ];
+
+        self.emit(&op);
+    }
+
+    pub fn cmpu32_reg(&mut self, register: Register, imm: u32) {
+        let mut op = [
+            self.rexw_rm(register),
+            0x81,
+            self.modrm(0b11, 7, register as u8),
+            0,
+            0,
+            0,
+            0,
+        ];
+
+        op[3..].copy_from_slice(&imm.to_le_bytes());
 
         self.emit(&op);
     }
 
+    // unsigned below
+    pub fn jbu8(&mut self, offset: u8) {
+        self.emit(&[0x72, offset]);
+    }
+
+    pub fn jneu8(&mut self, offset: u8) {
+        self.emit(&[0x75, offset]);
+    }
+
+    pub fn jeu8(&mut self, offset: u8) {
+        self.emit(&[0x74, offset]);
+    }
+
+    /// Points the short jump emitted at `jump_offset` to the current index
+    pub fn patch_jumpu8(&mut self, jump_offset: usize) {
+        let distance = self.index - (jump_offset + 2);
+        self.buffer[jump_offset + 1] = i8::try_from(distance).expect("short jump overflowed") as u8;
+    }
+
     pub fn jneu32(&mut self, offset: u32) {
         let mut op = [0x0f, 0x85, 0, 0, 0, 0];
 
@@ -152,6 +259,22 @@ impl<'a> Emitter<'a> {
         self.emit(&op);
     }
 
+    pub fn jmpu32(&mut self, offset: u32) {
+        let mut op = [0xe9, 0, 0, 0, 0];
+
+        op[1..].copy_from_slice(&offset.to_le_bytes());
+
+        self.emit(&op);
+    }
+
+    pub fn callu32(&mut self, offset: u32) {
+        let mut op = [0xe8, 0, 0, 0, 0];
+
+        op[1..].copy_from_slice(&offset.to_le_bytes());
+
+        self.emit(&op);
+    }
+
     pub fn call64(&mut self, register: Register) {
         if let Some(rexrm) = self.rex_rm(register) {
             let op = [rexrm, 0xff, self.modrm(0b11, 2, register as u8)];
@@ -182,6 +305,63 @@ impl<'a> Emitter<'a> {
         }
     }
 
+    pub fn ret(&mut self) {
+        self.emit(&[0xc3]);
+    }
+
+    pub fn syscall(&mut self) {
+        self.emit(&[0x0f, 0x05]);
+    }
+
+    // zero extends into the whole register, like every 32 bit operation
+    pub fn mov32_imm(&mut self, register: Register, imm: u32) {
+        let opcode = 0xb8 + (register as u8 & 0b111);
+        let le_bytes = imm.to_le_bytes();
+
+        if let Some(rexrm) = self.rex_rm(register) {
+            let op = [
+                rexrm,
+                opcode,
+                le_bytes[0],
+                le_bytes[1],
+                le_bytes[2],
+                le_bytes[3],
+            ];
+            self.emit(&op);
+        } else {
+            let op = [opcode, le_bytes[0], le_bytes[1], le_bytes[2], le_bytes[3]];
+            self.emit(&op);
+        }
+    }
+
+    // offset is relative to the next instruction, same as jumps
+    pub fn lea64_rip(&mut self, dst: Register, offset: u32) {
+        let mut op = [
+            self.rexw_r(dst),
+            0x8d,
+            self.modrm(0b00, dst as u8, 0b101),
+            0,
+            0,
+            0,
+            0,
+        ];
+
+        op[3..].copy_from_slice(&offset.to_le_bytes());
+
+        self.emit(&op);
+    }
+
+    // rsp, rbp, r12 and r13 need a SIB byte or a displacement as the address, so src can't be one
+    pub fn mov64_reg_ptr(&mut self, dst: Register, src: Register) {
+        let op = [
+            self.rexw_r_rm(dst, src),
+            0x8b,
+            self.modrm(0b00, dst as u8, src as u8),
+        ];
+
+        self.emit(&op);
+    }
+
     // I chose to match Intel's syntax for movs to keep my sanity while debugging
     pub fn mov64_reg(&mut self, dst: Register, src: Register) {
         let op = [
@@ -1,57 +1,897 @@
+pub mod bfasm;
+pub mod bfl;
 pub mod brainfuck;
+pub mod cache;
+pub mod codegen;
+pub mod debugger;
 pub mod ir;
 pub mod jit;
+pub mod repl;
+pub mod source;
+pub mod tape;
+pub mod textgen;
+pub mod tiered;
 
-use clap::arg_enum;
+use clap::{arg_enum, AppSettings};
 use structopt::StructOpt;
 
 use std::error::Error;
-use std::fs::File;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::fs::OpenOptions;
 use std::io;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 arg_enum! {
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
     enum Vm {
         RawBf,
         Bfr,
         Jit,
+        Tiered,
+        Wasm,
+    }
+}
+
+/// Something to write out for a program, to stdout unless a path is given
+#[derive(Debug)]
+struct Emit {
+    kind: EmitKind,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+enum EmitKind {
+    Ir,
+    MachineCode,
+    Asm,
+    C,
+    Wasm,
+    Wat,
+    Gas,
+    Nasm,
+    Rust,
+    Llvm,
+    Js,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, path) = match s.find('=') {
+            Some(idx) => (&s[..idx], Some(PathBuf::from(&s[idx + 1..]))),
+            None => (s, None),
+        };
+
+        let kind = match kind {
+            "ir" => EmitKind::Ir,
+            "machine-code" => EmitKind::MachineCode,
+            "asm" => EmitKind::Asm,
+            "c" => EmitKind::C,
+            "wasm" => EmitKind::Wasm,
+            "wat" => EmitKind::Wat,
+            "gas" => EmitKind::Gas,
+            "nasm" => EmitKind::Nasm,
+            "rust" => EmitKind::Rust,
+            "llvm" => EmitKind::Llvm,
+            "js" => EmitKind::Js,
+            _ => return Err(format!("unknown kind of output: {}", kind)),
+        };
+
+        Ok(Emit { kind, path })
+    }
+}
+
+/// Where a program comes from, shared by every subcommand that takes one
+#[derive(StructOpt, Debug)]
+struct ProgramOpt {
+    /// Path to the program, or - to read it from stdin. Brainfuck programs can carry their input
+    /// after a `!`
+    #[structopt(parse(from_os_str), required_unless = "code", conflicts_with = "code")]
+    program: Option<PathBuf>,
+    /// Brainfuck code to use instead of a program from a file
+    #[structopt(short = "e", long)]
+    code: Option<String>,
+}
+
+impl ProgramOpt {
+    /// Whether the program is read from stdin
+    fn is_stdin(&self) -> bool {
+        self.code.is_none() && self.program.as_deref() == Some(Path::new("-"))
+    }
+
+    /// How to refer to the program in messages
+    fn name(&self) -> String {
+        match &self.program {
+            Some(path) if path == Path::new("-") => String::from("<stdin>"),
+            Some(path) => path.display().to_string(),
+            None => String::from("<code>"),
+        }
+    }
+
+    /// Reads the program as brainfuck source code
+    fn source(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match (&self.code, &self.program) {
+            (Some(code), _) => Ok(code.clone().into_bytes()),
+            (None, Some(path)) if path == Path::new("-") => {
+                let mut source = Vec::new();
+                io::stdin().read_to_end(&mut source)?;
+                Ok(source)
+            }
+            (None, Some(path)) => source::read(path),
+            (None, None) => unreachable!("clap requires a program or some code"),
+        }
+    }
+
+    fn parse(&self) -> Result<Vec<brainfuck::Instruction>, Box<dyn Error>> {
+        Ok(brainfuck::parse(self.source()?))
+    }
+}
+
+/// How programs behave, for the subcommands that can change it
+///
+/// Only `--vm wasm` and the generated source backends honour these (gas and nasm only the tape
+/// size). The other vms, `compile` and `debug` are built around a fixed tape of 30000 cells that
+/// leaves the cell alone at the end of input, and refuse anything else. Cells are always 8 bits and
+/// wrap around, there's no option for their width
+#[derive(StructOpt, Debug)]
+struct Semantics {
+    /// Number of cells in the tape. Only --vm wasm and generated source can change it
+    #[structopt(long, default_value = "30000")]
+    tape_size: usize,
+    /// What reads at the end of input do: unchanged, zero or minus-one. Only --vm wasm and
+    /// generated source other than gas and nasm can change it
+    #[structopt(long, default_value = "unchanged")]
+    eof: codegen::Eof,
+}
+
+impl Semantics {
+    fn options(&self) -> codegen::Options {
+        codegen::Options {
+            tape_size: self.tape_size,
+            eof: self.eof,
+        }
+    }
+}
+
+/// Cached machine code is mapped executable after nothing more than a checksum, which only catches
+/// accidents. Anyone who can write to the cache directory can run code as whoever uses it, so
+/// directories owned by someone else or writable by others are refused
+#[derive(StructOpt, Debug)]
+struct CacheOpt {
+    /// Caches transformed and JIT compiled programs in $XDG_CACHE_HOME/bfr, so repeated runs start faster
+    #[structopt(long)]
+    cache: bool,
+    /// Caches programs in this directory instead, implies --cache. Cached code gets run as is, so
+    /// the directory must be yours and not writable by anyone else
+    #[structopt(long, parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+}
+
+impl CacheOpt {
+    fn open(&self) -> Option<cache::Cache> {
+        let dir = match &self.cache_dir {
+            Some(dir) => dir.clone(),
+            None if self.cache => match cache::Cache::default_dir() {
+                Some(dir) => dir,
+                None => clap::Error::with_description(
+                    "no cache directory, set XDG_CACHE_HOME or use --cache-dir",
+                    clap::ErrorKind::ValueValidation,
+                )
+                .exit(),
+            },
+            None => return None,
+        };
+
+        if let Err(message) = check_cache_dir(&dir) {
+            clap::Error::with_description(&message, clap::ErrorKind::ValueValidation).exit();
+        }
+
+        Some(cache::Cache::new(dir))
+    }
+}
+
+/// Makes sure nobody else could have put code in a cache directory
+fn check_cache_dir(dir: &Path) -> Result<(), String> {
+    let metadata = match fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        // it gets created, by us, when the first entry is stored
+        Err(_) => return Ok(()),
+    };
+
+    // getuid has no preconditions and can't fail
+    let uid = unsafe { libc::getuid() };
+
+    if metadata.uid() != uid {
+        Err(format!(
+            "refusing to use {} as a cache, it belongs to another user",
+            dir.display()
+        ))
+    } else if metadata.mode() & 0o022 != 0 {
+        Err(format!(
+            "refusing to use {} as a cache, other users can write to it",
+            dir.display()
+        ))
+    } else {
+        Ok(())
     }
 }
 
 #[derive(StructOpt, Debug)]
+#[structopt(setting = AppSettings::SubcommandRequiredElseHelp)]
 struct Opt {
-    #[structopt(short, long, possible_values = &Vm::variants(), case_insensitive = true)]
-    vm: Vm,
-    #[structopt(parse(from_os_str))]
-    program: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let opt = Opt::from_args();
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Runs a program
+    Run {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+        /// Input for the program, read from a file if one exists at this path and taken as text
+        /// otherwise. Defaults to the input after a `!` in the program, and then to stdin
+        #[structopt(long, parse(from_os_str))]
+        input: Option<OsString>,
+        #[structopt(short, long, default_value = "jit", possible_values = &Vm::variants(), case_insensitive = true)]
+        vm: Vm,
+        /// Writes symbols for JIT compiled code to /tmp/perf-<pid>.map. Only --vm jit can
+        #[structopt(long)]
+        perf_map: bool,
+        #[structopt(flatten)]
+        cache: CacheOpt,
+        #[structopt(flatten)]
+        semantics: Semantics,
+    },
+    /// Compiles a program ahead of time into a standalone x86_64 Linux executable
+    Compile {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+        /// Emits a relocatable object exporting the program as a C function instead
+        #[structopt(short = "c", long)]
+        object: bool,
+        /// Name of the function exported by --object
+        #[structopt(long, default_value = "bf_main")]
+        symbol: String,
+        #[structopt(flatten)]
+        semantics: Semantics,
+    },
+    /// Writes out code for a program
+    Emit {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+        /// What to write, as <kind>[=<file>], to stdout unless a file is given. Kinds are ir,
+        /// machine-code, asm (a listing of the JIT's code), c, wasm, wat, gas, nasm, rust, llvm or js
+        #[structopt(short, long, required = true, number_of_values = 1)]
+        to: Vec<Emit>,
+        #[structopt(flatten)]
+        semantics: Semantics,
+    },
+    /// Checks that a program is well formed, without running it
+    Check {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+    },
+    /// Times a program on several vms, with the same input, and checks they agree on its output
+    Bench {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+        /// Input for the program, like for run
+        #[structopt(long, parse(from_os_str))]
+        input: Option<OsString>,
+        /// Vms to compare, every one but rawbf unless given
+        #[structopt(short, long, possible_values = &Vm::variants(), case_insensitive = true, use_delimiter = true)]
+        vm: Vec<Vm>,
+        /// Times to run the program on each vm
+        #[structopt(short, long, default_value = "3")]
+        runs: usize,
+        #[structopt(flatten)]
+        semantics: Semantics,
+    },
+    /// Steps through a program interactively
+    Debug {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+        /// Input for the program, like for run, except it's empty by default as the debugger reads
+        /// commands from stdin
+        #[structopt(long, parse(from_os_str))]
+        input: Option<OsString>,
+        #[structopt(flatten)]
+        semantics: Semantics,
+    },
+    /// Runs brainfuck line by line, against a tape that sticks around
+    Repl,
+    /// Rewrites a program as smaller brainfuck, without comments, redundant increments or dead loops
+    Optimize {
+        #[structopt(flatten)]
+        program: ProgramOpt,
+        /// Where to write the optimized program, instead of stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Generates a short program that prints some text
+    GenText {
+        #[structopt(parse(from_os_str))]
+        text: OsString,
+        /// Prints a newline after the text
+        #[structopt(short, long)]
+        newline: bool,
+        /// Where to write the program, instead of stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Compiles a BFL program, or a .bfa program for the macro assembler, into brainfuck, written
+    /// next to it as a .b file unless it's run
+    Build {
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+        /// Where to write the brainfuck, instead of the program's path with a .b extension
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Runs the compiled program on this vm instead of writing it out
+        #[structopt(short, long, possible_values = &Vm::variants(), case_insensitive = true)]
+        vm: Option<Vm>,
+        #[structopt(flatten)]
+        semantics: Semantics,
+    },
+}
 
-    let mut buf = Vec::new();
-    let mut bf = File::open(opt.program)?;
-    bf.read_to_end(&mut buf)?;
+/// Where a program reads from: --input, the input after a `!` in its source, or the fallback
+fn program_input(
+    input: Option<&OsStr>,
+    source: &[u8],
+    fallback: impl Read + 'static,
+) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    if let Some(input) = input {
+        let path = Path::new(input);
+        let data = if path.is_file() {
+            fs::read(path)?
+        } else {
+            input.as_bytes().to_vec()
+        };
 
-    let parsed_bf = brainfuck::parse(buf);
+        return Ok(Box::new(io::Cursor::new(data)));
+    }
 
-    let mut stdin = io::stdin();
-    let mut stdout = io::stdout();
+    match brainfuck::split_input(source) {
+        (_, Some(embedded)) => Ok(Box::new(io::Cursor::new(embedded.to_vec()))),
+        (_, None) => Ok(Box::new(fallback)),
+    }
+}
+
+fn prepare(source: &[u8], assemble: bool) -> Result<cache::Entry, Box<dyn Error>> {
+    let parsed_bf = brainfuck::parse(source.iter().copied());
+    let instructions = ir::transform(&parsed_bf)?;
+    let assembly = if assemble {
+        Some(jit::assemble(&instructions, jit::Io::Trampolines))
+    } else {
+        None
+    };
+
+    Ok(cache::Entry {
+        instructions,
+        assembly,
+    })
+}
+
+/// Gets a program ready to run, from the cache if possible
+fn load(
+    source: &[u8],
+    assemble: bool,
+    cache: Option<&cache::Cache>,
+) -> Result<cache::Entry, Box<dyn Error>> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return prepare(source, assemble),
+    };
+
+    // input after a ! doesn't change the program, so it shouldn't store another copy of it
+    let (code, _) = brainfuck::split_input(source);
+    let key = cache::key(code, if assemble { "jit" } else { "ir" });
+
+    // a broken or outdated entry is just a miss, and gets overwritten
+    if let Ok(Some(entry)) = cache.load(key) {
+        if entry.assembly.is_some() == assemble {
+            return Ok(entry);
+        }
+    }
+
+    let entry = prepare(source, assemble)?;
+
+    // the program can run without the cache, so it's not worth stopping over
+    if let Err(err) = cache.store(key, &entry) {
+        eprintln!("warning: {}", err);
+    }
+
+    Ok(entry)
+}
+
+/// The interpreters and the JIT always have 30000 cells, and leave the cell alone at the end of input
+fn require_default_semantics(what: &str, options: &codegen::Options) -> Result<(), Box<dyn Error>> {
+    let default = codegen::Options::default();
+
+    if options.tape_size != default.tape_size || options.eof != default.eof {
+        return Err(format!(
+            "{} always has {} cells and leaves the cell unchanged on EOF",
+            what, default.tape_size
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Only the wasm vm can run with other semantics
+fn supports_semantics(vm: Vm, options: &codegen::Options) -> Result<(), Box<dyn Error>> {
+    if vm == Vm::Wasm {
+        return Ok(());
+    }
 
-    match opt.vm {
-        Vm::RawBf => brainfuck::Vm::new(parsed_bf).vm_loop(&mut stdin, &mut stdout)?,
+    require_default_semantics(&format!("--vm {}", vm.to_string().to_lowercase()), options)
+}
+
+fn run(
+    vm: Vm,
+    source: &[u8],
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    perf_map: bool,
+    cache: Option<&cache::Cache>,
+    options: &codegen::Options,
+) -> Result<(), Box<dyn Error>> {
+    supports_semantics(vm, options)?;
+
+    if perf_map && vm != Vm::Jit {
+        return Err(format!(
+            "--vm {} doesn't write a perf map, only --vm jit does",
+            vm.to_string().to_lowercase()
+        )
+        .into());
+    }
+
+    match vm {
+        Vm::RawBf => {
+            let parsed_bf = brainfuck::parse(source.iter().copied());
+            brainfuck::Vm::new(parsed_bf).vm_loop(input, output)?;
+        }
         Vm::Bfr => {
-            let ir = ir::transform(&parsed_bf)?;
-            ir::Vm::new(ir).vm_loop(&mut stdin, &mut stdout)?;
+            let ir = load(source, false, cache)?.instructions;
+            ir::Vm::new(ir).vm_loop(input, output)?;
         }
         Vm::Jit => {
-            let ir = ir::transform(&parsed_bf)?;
-            let program = jit::transform(&ir);
-            jit::Vm::new(program).vm_loop(&mut stdin, &mut stdout);
+            let entry = load(source, true, cache)?;
+            let assembly = entry.assembly.expect("entry was loaded with assembly");
+            let program = jit::load(&entry.instructions, &assembly)?;
+
+            if perf_map {
+                jit::perf::write_map(&program)?;
+            }
+
+            jit::Vm::new(program)?.vm_loop(input, output);
+        }
+        Vm::Tiered => {
+            let ir = load(source, false, cache)?.instructions;
+            tiered::Vm::new(ir).vm_loop(input, output)?;
+        }
+        Vm::Wasm => {
+            let ir = load(source, false, cache)?.instructions;
+            let wasm = codegen::wasm::lower(&ir, options).encode();
+            codegen::wasm::interp::Instance::new(&wasm)?.run(input, output)?;
         }
     }
 
     Ok(())
 }
+
+fn compile(
+    program: &ProgramOpt,
+    output: &Path,
+    object: Option<&str>,
+    options: &codegen::Options,
+) -> Result<(), Box<dyn Error>> {
+    require_default_semantics("compiled code", options)?;
+
+    // compiled programs read stdin, so they'd run differently than they do with bfr run
+    let source = program.source()?;
+    if let Some((line, column)) = input_marker(&source) {
+        return Err(format!(
+            "the program carries input after the ! at {}:{}, which compiled code can't, remove it \
+             and give the input on stdin instead",
+            line, column
+        )
+        .into());
+    }
+
+    let parsed_bf = brainfuck::parse(source);
+    let ir = ir::transform(&parsed_bf)?;
+
+    let (contents, mode) = match object {
+        Some(symbol) => {
+            let assembly = jit::assemble(&ir, jit::Io::Trampolines);
+            (jit::elf::object(&assembly.code, symbol), 0o644)
+        }
+        None => {
+            let assembly = jit::assemble(&ir, jit::Io::Syscalls);
+            (jit::elf::executable(&assembly.code), 0o755)
+        }
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(output)?;
+    file.write_all(&contents)?;
+
+    Ok(())
+}
+
+fn optimize(program: &ProgramOpt, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let parsed_bf = program.parse()?;
+    let ir = ir::transform(&parsed_bf)?;
+    let optimized = codegen::bf::generate(&ir);
+
+    match output {
+        Some(path) => fs::write(path, optimized)?,
+        None => io::stdout().write_all(optimized.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+fn gen_text(text: &OsString, newline: bool, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut bytes = text.as_bytes().to_vec();
+    if newline {
+        bytes.push(b'\n');
+    }
+
+    let mut program = textgen::generate(&bytes)?;
+    program.push('\n');
+
+    match output {
+        Some(path) => fs::write(path, program)?,
+        None => io::stdout().write_all(program.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+fn build(
+    program: &Path,
+    output: Option<&Path>,
+    vm: Option<Vm>,
+    options: &codegen::Options,
+) -> Result<(), Box<dyn Error>> {
+    let compiled = if source::is_assembly(program) {
+        bfasm::assemble(program)?
+    } else {
+        bfl::compile(&fs::read_to_string(program)?)?
+    };
+    let ir = ir::transform(&compiled)?;
+    let bf = codegen::bf::generate(&ir);
+
+    match vm {
+        Some(vm) => run(
+            vm,
+            bf.as_bytes(),
+            &mut io::stdin(),
+            &mut io::stdout(),
+            false,
+            None,
+            options,
+        ),
+        None => {
+            let output = match output {
+                Some(path) => path.to_path_buf(),
+                None => program.with_extension("b"),
+            };
+            fs::write(output, bf)?;
+
+            Ok(())
+        }
+    }
+}
+
+fn emit(
+    program: &ProgramOpt,
+    emits: &[Emit],
+    options: &codegen::Options,
+) -> Result<(), Box<dyn Error>> {
+    let parsed_bf = program.parse()?;
+    let ir = ir::transform(&parsed_bf)?;
+    let assembly = jit::assemble(&ir, jit::Io::Trampolines);
+
+    for emit in emits {
+        let contents = match emit.kind {
+            EmitKind::Ir => ir
+                .iter()
+                .enumerate()
+                .map(|(idx, instr)| format!("{}: {:?}\n", idx, instr))
+                .collect::<String>()
+                .into_bytes(),
+            EmitKind::MachineCode => assembly.code.clone(),
+            EmitKind::Asm => jit::disasm::listing(&ir, &assembly).into_bytes(),
+            EmitKind::C => codegen::c::generate(&ir, options).into_bytes(),
+            EmitKind::Rust => codegen::rust::generate(&ir, options).into_bytes(),
+            EmitKind::Llvm => codegen::llvm::generate(&ir, options).into_bytes(),
+            EmitKind::Js => codegen::js::generate(&ir, options).into_bytes(),
+            EmitKind::Wasm => codegen::wasm::lower(&ir, options).encode(),
+            EmitKind::Wat => codegen::wasm::lower(&ir, options).to_wat().into_bytes(),
+            EmitKind::Gas | EmitKind::Nasm => {
+                if options.eof != codegen::Eof::Unchanged {
+                    return Err("x86 assembly always leaves the cell unchanged on EOF".into());
+                }
+
+                let syntax = match emit.kind {
+                    EmitKind::Gas => jit::asm::Syntax::Gas,
+                    _ => jit::asm::Syntax::Nasm,
+                };
+                jit::asm::generate(&ir, syntax, options.tape_size).into_bytes()
+            }
+        };
+
+        match &emit.path {
+            Some(path) => fs::write(path, contents)?,
+            None => io::stdout().write_all(&contents)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Where the `!` that starts a program's input is, as a line and column
+fn input_marker(source: &[u8]) -> Option<(usize, usize)> {
+    let idx = source.iter().position(|&byte| byte == b'!')?;
+    let before = &source[..idx];
+    let line = before.iter().filter(|&&byte| byte == b'\n').count() + 1;
+    let column = idx
+        - before
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |nl| nl + 1)
+        + 1;
+
+    Some((line, column))
+}
+
+/// Checks brackets match up, pointing at the first one that doesn't
+fn check_brackets(source: &[u8]) -> Result<usize, Box<dyn Error>> {
+    let mut open = Vec::new();
+    let mut deepest = 0;
+    let (mut line, mut column) = (1, 0);
+
+    for &byte in source {
+        column += 1;
+
+        match byte {
+            b'\n' => {
+                line += 1;
+                column = 0;
+            }
+            b'[' => {
+                open.push((line, column));
+                deepest = deepest.max(open.len());
+            }
+            b']' if open.pop().is_none() => {
+                return Err(format!("unmatched ] at {}:{}", line, column).into());
+            }
+            _ => (),
+        }
+    }
+
+    match open.pop() {
+        Some((line, column)) => Err(format!("unclosed [ at {}:{}", line, column).into()),
+        None => Ok(deepest),
+    }
+}
+
+fn check(program: &ProgramOpt) -> Result<(), Box<dyn Error>> {
+    let source = program.source()?;
+    let marker = input_marker(&source);
+    let (source, input) = brainfuck::split_input(&source);
+
+    // a ! in a comment cuts the program short, which is easy to miss
+    if let (Some((line, column)), Some(input)) = (marker, input) {
+        let commands = brainfuck::parse(input.iter().copied()).len();
+
+        if commands > 0 {
+            eprintln!(
+                "warning: everything after the ! at {}:{} is input, including {} brainfuck commands",
+                line, column, commands
+            );
+        }
+    }
+
+    let deepest = check_brackets(source)?;
+    // the IR keeps track of open loops in a fixed size stack
+    if deepest > 32 {
+        return Err(format!(
+            "loops nest {} deep, but only 32 levels are supported",
+            deepest
+        )
+        .into());
+    }
+
+    let parsed_bf = brainfuck::parse(source.iter().copied());
+    let ir = ir::transform(&parsed_bf)?;
+    println!(
+        "{}: ok, {} instructions, {} after optimization, loops nest {} deep",
+        program.name(),
+        parsed_bf.len(),
+        ir.len(),
+        deepest
+    );
+
+    Ok(())
+}
+
+fn bench(
+    program: &ProgramOpt,
+    input: Option<&OsStr>,
+    vms: &[Vm],
+    runs: usize,
+    options: &codegen::Options,
+) -> Result<(), Box<dyn Error>> {
+    let source = program.source()?;
+    let mut data = Vec::new();
+    program_input(input, &source, io::stdin())?.read_to_end(&mut data)?;
+
+    let vms = if vms.is_empty() {
+        &[Vm::Bfr, Vm::Jit, Vm::Tiered, Vm::Wasm][..]
+    } else {
+        vms
+    };
+
+    for &vm in vms {
+        supports_semantics(vm, options)?;
+    }
+
+    let mut expected: Option<Vec<u8>> = None;
+
+    for &vm in vms {
+        let mut times = Vec::with_capacity(runs);
+        let mut output = Vec::new();
+
+        for _ in 0..runs.max(1) {
+            output.clear();
+            let start = Instant::now();
+            run(
+                vm,
+                &source,
+                &mut &data[..],
+                &mut output,
+                false,
+                None,
+                options,
+            )?;
+            times.push(start.elapsed());
+        }
+
+        let best = times.iter().min().expect("ran at least once");
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+
+        let agrees = match &expected {
+            Some(expected) if *expected != output => "  output differs!",
+            Some(_) => "",
+            None => {
+                expected = Some(output);
+                ""
+            }
+        };
+
+        println!(
+            "{:<8} best {:>12.3?}  mean {:>12.3?}{}",
+            vm.to_string().to_lowercase(),
+            best,
+            mean,
+            agrees
+        );
+    }
+
+    Ok(())
+}
+
+fn debug(
+    program: &ProgramOpt,
+    input: Option<&OsStr>,
+    options: &codegen::Options,
+) -> Result<(), Box<dyn Error>> {
+    require_default_semantics("the debugger", options)?;
+
+    if program.is_stdin() {
+        return Err(
+            "the debugger reads commands from stdin, so the program can't come from there too"
+                .into(),
+        );
+    }
+
+    let source = program.source()?;
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut input = program_input(input, &source, io::empty())?;
+
+    debugger::Debugger::new(&source, &mut input, &mut stdout)?.run(&mut stdin.lock())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+
+    match opt.command {
+        Command::Run {
+            program,
+            input,
+            vm,
+            perf_map,
+            cache,
+            semantics,
+        } => {
+            let source = program.source()?;
+            let mut input = program_input(input.as_deref(), &source, io::stdin())?;
+            let cache = cache.open();
+            run(
+                vm,
+                &source,
+                &mut input,
+                &mut io::stdout(),
+                perf_map,
+                cache.as_ref(),
+                &semantics.options(),
+            )
+        }
+        Command::Compile {
+            program,
+            output,
+            object,
+            symbol,
+            semantics,
+        } => {
+            let object = if object { Some(symbol.as_str()) } else { None };
+            compile(&program, &output, object, &semantics.options())
+        }
+        Command::Emit {
+            program,
+            to,
+            semantics,
+        } => emit(&program, &to, &semantics.options()),
+        Command::Check { program } => check(&program),
+        Command::Bench {
+            program,
+            input,
+            vm,
+            runs,
+            semantics,
+        } => bench(&program, input.as_deref(), &vm, runs, &semantics.options()),
+        Command::Debug {
+            program,
+            input,
+            semantics,
+        } => debug(&program, input.as_deref(), &semantics.options()),
+        Command::Repl => {
+            let stdin = io::stdin();
+            repl::Repl::default().run(&mut stdin.lock(), &mut io::stdout())
+        }
+        Command::Optimize { program, output } => optimize(&program, output.as_deref()),
+        Command::GenText {
+            text,
+            newline,
+            output,
+        } => gen_text(&text, newline, output.as_deref()),
+        Command::Build {
+            program,
+            output,
+            vm,
+            semantics,
+        } => build(&program, output.as_deref(), vm, &semantics.options()),
+    }
+}
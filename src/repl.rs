@@ -0,0 +1,230 @@
+#![forbid(unsafe_code)]
+
+/// A brainfuck REPL, where every line runs against the same tape
+///
+/// Lines with an unclosed `[` keep reading more lines until it's closed, and lines starting with
+/// `:` are commands for the REPL itself, even in the middle of a block. Reads take bytes from the
+/// same place lines come from
+use std::error::Error;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+use crate::{brainfuck, ir, source, tape};
+
+const HELP: &str = "\
+:tape [from..to]  shows cells, around the data pointer unless a range is given
+:load <file>      runs a program from a file against the tape, compiling .bfa and .bfl first
+:cancel           throws away a block that isn't closed yet
+:reset            clears the tape and moves the data pointer back to the first cell
+:help             shows this
+:quit             leaves the REPL
+";
+
+/// How many cells on each side of the data pointer are shown after every line
+const CONTEXT: usize = 5;
+
+pub struct Repl {
+    vm: ir::Vm,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl {
+            vm: ir::Vm::new(Vec::new()),
+        }
+    }
+}
+
+/// How far brackets are from balancing out, negative if there's an unmatched `]`
+fn depth(source: &str) -> isize {
+    // a `]` with nothing to close can't be balanced by anything after it
+    let depth = source.bytes().try_fold(0, |depth, byte| match byte {
+        b'[' => Ok(depth + 1),
+        b']' if depth == 0 => Err(-1),
+        b']' => Ok(depth - 1),
+        _ => Ok(depth),
+    });
+
+    depth.unwrap_or_else(|unmatched| unmatched)
+}
+
+impl Repl {
+    /// Runs the program loaded in the vm, stopping before the data pointer could leave the tape
+    fn execute(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        while !self.vm.is_finished() {
+            if self.vm.leaves_tape() {
+                return Err("stopped, the data pointer would move off the tape".into());
+            }
+
+            self.vm.step(input, output)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_pointer(&self, output: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let pointer = self.vm.data_pointer();
+        tape::show(
+            self.vm.cells(),
+            pointer,
+            pointer.saturating_sub(CONTEXT),
+            pointer + CONTEXT + 1,
+            output,
+        )?;
+
+        Ok(())
+    }
+
+    /// Runs some brainfuck against the tape
+    fn run_source(
+        &mut self,
+        source: Vec<u8>,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let instructions = ir::transform(&brainfuck::parse(source))?;
+        self.vm.load(instructions);
+
+        // the program's output is held back, so the tape can start on a line of its own
+        let mut printed = Vec::new();
+        let result = self.execute(input, &mut printed);
+
+        output.write_all(&printed)?;
+        if printed.last().is_some_and(|&byte| byte != b'\n') {
+            writeln!(output)?;
+        }
+
+        result
+    }
+
+    /// Runs a command for the REPL, returning false once it should stop
+    fn command(
+        &mut self,
+        line: &str,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let arg = words.next();
+
+        match command {
+            ":tape" => match arg {
+                Some(range) => match tape::parse_range(range) {
+                    Some((from, to)) => {
+                        tape::show(self.vm.cells(), self.vm.data_pointer(), from, to, output)?
+                    }
+                    None => writeln!(output, ":tape takes a range of cells, like 0..20")?,
+                },
+                None => self.show_pointer(output)?,
+            },
+            ":load" => match arg {
+                // lines come from stdin too, so there's nothing left to load from there
+                Some("-") => writeln!(output, ":load takes a file, not stdin")?,
+                Some(path) => {
+                    let source = source::read(Path::new(path))?;
+                    self.run_source(source, input, output)?;
+                    self.show_pointer(output)?;
+                }
+                None => writeln!(output, ":load takes a path to a program")?,
+            },
+            ":reset" => {
+                *self = Repl::default();
+                self.show_pointer(output)?;
+            }
+            ":help" => write!(output, "{}", HELP)?,
+            ":quit" | ":q" => return Ok(false),
+            _ => writeln!(output, "unknown command {}, try :help", command)?,
+        }
+
+        Ok(true)
+    }
+
+    /// Reads and runs lines until they run out or :quit
+    pub fn run<R: BufRead>(
+        &mut self,
+        input: &mut R,
+        output: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut pending = String::new();
+
+        loop {
+            write!(
+                output,
+                "{}",
+                if pending.is_empty() { "bf> " } else { "... " }
+            )?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let result = if line.trim() == ":cancel" {
+                pending.clear();
+                Ok(())
+            } else if line.trim_start().starts_with(':') {
+                match self.command(line.trim(), input, output) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => return Ok(()),
+                    Err(err) => Err(err),
+                }
+            } else {
+                pending.push_str(&line);
+
+                match depth(&pending) {
+                    0 => {
+                        let source = std::mem::take(&mut pending).into_bytes();
+                        self.run_source(source, input, output)
+                            .and_then(|()| self.show_pointer(output))
+                    }
+                    depth if depth < 0 => {
+                        pending.clear();
+                        Err("unmatched ]".into())
+                    }
+                    _ => Ok(()),
+                }
+            };
+
+            // mistakes shouldn't throw away the tape
+            if let Err(err) = result {
+                writeln!(output, "error: {}", err)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(lines: &str) -> String {
+        let mut output = Vec::new();
+        Repl::default()
+            .run(&mut lines.as_bytes(), &mut output)
+            .unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn an_unmatched_close_bracket_is_an_error_even_if_an_open_one_follows() {
+        assert_eq!(depth("]["), -1);
+        assert!(run("][\n").contains("error: unmatched ]"));
+    }
+
+    #[test]
+    fn blocks_span_lines_and_commands_work_in_the_middle_of_them() {
+        let output = run("+++[>+\n:tape 0..2\n<-]\n:tape 0..2\n");
+
+        // the block only runs once it's closed
+        assert!(output.starts_with("bf> ... "));
+        assert!(output.contains("\n [  0]    0 \n"));
+        assert!(output.ends_with("\n [  0]    3 \nbf> "));
+    }
+}
@@ -0,0 +1,34 @@
+#![forbid(unsafe_code)]
+
+/// Reading programs from files, whichever language they're written in
+///
+/// `.bfa` files go through the macro assembler and `.bfl` files through the BFL compiler, and
+/// everything else is taken to be brainfuck already
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::{bfasm, bfl, brainfuck};
+
+/// Whether a program is written for the macro assembler instead of being plain brainfuck
+pub fn is_assembly(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "bfa")
+}
+
+/// Whether a program is written in BFL, instead of being plain brainfuck
+pub fn is_bfl(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "bfl")
+}
+
+/// Reads a program from a file as brainfuck source code, compiling it first if it isn't brainfuck
+pub fn read(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if is_assembly(path) {
+        Ok(brainfuck::unparse(&bfasm::assemble(path)?))
+    } else if is_bfl(path) {
+        Ok(brainfuck::unparse(&bfl::compile(&fs::read_to_string(
+            path,
+        )?)?))
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
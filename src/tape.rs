@@ -0,0 +1,39 @@
+#![forbid(unsafe_code)]
+
+/// Showing cells of a tape to someone at a prompt, for the REPL and the debugger
+use std::io;
+use std::io::Write;
+
+/// Writes the cells in `from..to` side by side under their indices, with the one under the data
+/// pointer in brackets
+pub fn show(
+    cells: &[u8],
+    pointer: usize,
+    from: usize,
+    to: usize,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    let to = to.min(cells.len());
+    let (mut indices, mut values) = (String::new(), String::new());
+
+    for (idx, cell) in cells.iter().enumerate().take(to).skip(from) {
+        indices.push_str(&format!("{:>6}", idx));
+
+        if idx == pointer {
+            values.push_str(&format!(" [{:>3}]", cell));
+        } else {
+            values.push_str(&format!("  {:>3} ", cell));
+        }
+    }
+
+    writeln!(output, "{}\n{}", indices, values)
+}
+
+/// Parses a range of cells written as `from..to`
+pub fn parse_range(arg: &str) -> Option<(usize, usize)> {
+    let idx = arg.find("..")?;
+    let from = arg[..idx].parse().ok()?;
+    let to = arg[idx + 2..].parse().ok()?;
+
+    Some((from, to))
+}
@@ -0,0 +1,240 @@
+#![forbid(unsafe_code)]
+
+/// Generates brainfuck programs that print some given bytes
+///
+/// A multiply loop first sets a few cells to values close to the bytes, and then every byte is
+/// printed from whichever cell gets to it with the fewest instructions. A bunch of loop sizes and
+/// cell counts are tried, and the shortest program wins
+use snafu::{ensure, ResultExt, Snafu};
+use std::io;
+
+use crate::{brainfuck, ir};
+
+/// Most cells the multiply loop sets up, besides its counter
+const MAX_CELLS: usize = 5;
+/// Largest loop counter to try
+const MAX_COUNTER: u32 = 20;
+
+#[derive(Snafu, Debug)]
+pub enum GenerateError {
+    #[snafu(display("Generated program is malformed"))]
+    Malformed { source: ir::TransformError },
+    #[snafu(display("Generated program failed to run"))]
+    FailedToRun { source: ir::VmError },
+    #[snafu(display("Generated program doesn't print the requested bytes"))]
+    WrongOutput,
+}
+
+fn repeat(program: &mut String, c: char, count: usize) {
+    program.extend(std::iter::repeat_n(c, count));
+}
+
+/// Splits the distinct bytes into `groups` runs, returning the median of each
+///
+/// Values are sorted, so the best split is made of contiguous runs, which a small dynamic program
+/// over where each run ends can find
+fn centers(values: &[(u8, usize)], groups: usize) -> Vec<u8> {
+    let count = values.len();
+
+    // cost[i][j]: how far values[i..j] are from their weighted median, and the median
+    let mut cost = vec![vec![(0usize, 0u8); count + 1]; count + 1];
+    for i in 0..count {
+        for j in i + 1..=count {
+            let total: usize = values[i..j].iter().map(|&(_, weight)| weight).sum();
+            let mut seen = 0;
+            let median = values[i..j]
+                .iter()
+                .find(|&&(_, weight)| {
+                    seen += weight;
+                    seen * 2 >= total
+                })
+                .unwrap()
+                .0;
+            let distance = values[i..j]
+                .iter()
+                .map(|&(value, weight)| {
+                    weight * (i32::from(value) - i32::from(median)).unsigned_abs() as usize
+                })
+                .sum();
+
+            cost[i][j] = (distance, median);
+        }
+    }
+
+    // best[g][j]: cheapest way to split values[..j] into g runs, and where the last run starts
+    let mut best = vec![vec![(usize::MAX, 0); count + 1]; groups + 1];
+    best[0][0] = (0, 0);
+    for g in 1..=groups {
+        for j in g..=count {
+            for i in g - 1..j {
+                if best[g - 1][i].0 != usize::MAX {
+                    let total = best[g - 1][i].0 + cost[i][j].0;
+                    if total < best[g][j].0 {
+                        best[g][j] = (total, i);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut medians = Vec::with_capacity(groups);
+    let mut end = count;
+    for g in (1..=groups).rev() {
+        let start = best[g][end].1;
+        medians.push(cost[start][end].1);
+        end = start;
+    }
+    medians.reverse();
+
+    medians
+}
+
+/// Builds a program whose loop runs `counter` times and adds `multipliers` to the cells after it
+fn candidate(bytes: &[u8], counter: u32, multipliers: &[u32]) -> String {
+    let mut program = String::new();
+    // the counter's cell ends up as zero, which is still good for printing small bytes
+    let mut cells = vec![0u8; multipliers.len() + 1];
+
+    if counter > 0 && !multipliers.is_empty() {
+        repeat(&mut program, '+', counter as usize);
+        program.push('[');
+        for (idx, &multiplier) in multipliers.iter().enumerate() {
+            program.push('>');
+            repeat(&mut program, '+', multiplier as usize);
+            cells[idx + 1] = (counter * multiplier % 256) as u8;
+        }
+        repeat(&mut program, '<', multipliers.len());
+        program.push_str("-]");
+    }
+
+    let mut position = 0usize;
+    for &byte in bytes {
+        let distance = |cell: usize, value: u8| {
+            let up = byte.wrapping_sub(value) as usize;
+            let moves = (position as isize - cell as isize).unsigned_abs();
+            moves + up.min(256 - up)
+        };
+
+        let (cell, _) = cells
+            .iter()
+            .enumerate()
+            .min_by_key(|&(cell, &value)| distance(cell, value))
+            .unwrap();
+
+        if cell > position {
+            repeat(&mut program, '>', cell - position);
+        } else {
+            repeat(&mut program, '<', position - cell);
+        }
+
+        let up = byte.wrapping_sub(cells[cell]) as usize;
+        if up <= 128 {
+            repeat(&mut program, '+', up);
+        } else {
+            repeat(&mut program, '-', 256 - up);
+        }
+
+        program.push('.');
+        cells[cell] = byte;
+        position = cell;
+    }
+
+    program
+}
+
+/// Runs a program through the parser and ir::Vm, and checks it prints exactly `bytes`
+fn verify(program: &str, bytes: &[u8]) -> Result<(), GenerateError> {
+    let parsed = brainfuck::parse(program.bytes());
+    let instructions = ir::transform(&parsed).context(Malformed)?;
+
+    let mut output = Vec::new();
+    ir::Vm::new(instructions)
+        .vm_loop(&mut io::empty(), &mut output)
+        .context(FailedToRun)?;
+
+    ensure!(output == bytes, WrongOutput);
+
+    Ok(())
+}
+
+/// Generates a short program that prints `bytes`
+pub fn generate(bytes: &[u8]) -> Result<String, GenerateError> {
+    let mut values: Vec<(u8, usize)> = Vec::new();
+    let mut sorted = bytes.to_vec();
+    sorted.sort_unstable();
+    for byte in sorted {
+        match values.last_mut() {
+            Some((value, weight)) if *value == byte => *weight += 1,
+            _ => values.push((byte, 1)),
+        }
+    }
+
+    // without a loop, everything is built up from the zero in the first cell
+    let mut best = candidate(bytes, 0, &[]);
+
+    for groups in 1..=MAX_CELLS.min(values.len()) {
+        let medians = centers(&values, groups);
+
+        for counter in 2..=MAX_COUNTER {
+            let multipliers: Vec<u32> = medians
+                .iter()
+                .map(|&median| ((u32::from(median) + counter / 2) / counter).max(1))
+                .collect();
+
+            let program = candidate(bytes, counter, &multipliers);
+            if program.len() < best.len() {
+                best = program;
+            }
+        }
+    }
+
+    verify(&best, bytes)?;
+
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck;
+
+    fn run(program: &str) -> Vec<u8> {
+        let instructions = ir::transform(&brainfuck::parse(program.bytes())).unwrap();
+        let mut output = Vec::new();
+
+        ir::Vm::new(instructions)
+            .vm_loop(&mut io::empty(), &mut output)
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    fn prints_the_text() {
+        let program = generate(b"Hello, World!\n").unwrap();
+
+        assert_eq!(run(&program), b"Hello, World!\n");
+        // nothing but brainfuck, so the output can be pasted anywhere
+        assert!(program.bytes().all(|byte| b"+-<>[].".contains(&byte)));
+    }
+
+    #[test]
+    fn prints_every_byte() {
+        let bytes: Vec<u8> = (0..=255).rev().collect();
+
+        assert_eq!(run(&generate(&bytes).unwrap()), bytes);
+    }
+
+    #[test]
+    fn prints_nothing() {
+        assert_eq!(generate(b"").unwrap(), "");
+    }
+
+    #[test]
+    fn beats_adding_up_to_each_byte() {
+        let text = b"aaaa";
+        let naive = usize::from(b'a') + text.len();
+
+        assert!(generate(text).unwrap().len() < naive / 2);
+    }
+}
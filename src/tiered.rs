@@ -0,0 +1,155 @@
+/// A tiered engine: interprets BFR IR and JIT compiles loops once they get hot
+///
+/// Short programs never pay for code generation, while long running ones spend most of their time
+/// in native code. The interpreter and the native code share the interpreter's cells
+///
+/// Compiled loops check every move of the data pointer, and hand back to the interpreter right
+/// before one would leave the tape, so it can stop the program the same way it would have
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::ir::{self, Instruction};
+use crate::jit;
+
+/// How many times a loop has to jump back to its start before it gets compiled
+const HOT_LOOP_THRESHOLD: u32 = 1000;
+
+#[derive(Snafu, Debug)]
+pub enum VmError {
+    #[snafu(display("Interpreter failed"))]
+    Interpreter { source: ir::VmError },
+    #[snafu(display("Failed to compile loop"))]
+    Compiler { source: jit::JitError },
+}
+
+pub struct Vm {
+    interpreter: ir::Vm,
+    /// How many times each loop jumped back to its start, indexed by its JumpForwardsIfZero
+    counters: Vec<u32>,
+    /// Native code for hot loops, indexed by their JumpForwardsIfZero
+    compiled: HashMap<usize, jit::CallableProgram>,
+}
+
+impl Vm {
+    /// Creates a new instance of a tiered vm, using a stream of instructions as the program
+    pub fn new(program: Vec<Instruction>) -> Self {
+        Vm {
+            counters: vec![0; program.len()],
+            interpreter: ir::Vm::new(program),
+            compiled: HashMap::new(),
+        }
+    }
+
+    fn compile(&mut self, start: usize, end: usize) -> Result<(), VmError> {
+        // jumps are relative, so a loop on its own is a perfectly fine program
+        let program = jit::transform_guarded(
+            &self.interpreter.program()[start..=end],
+            self.interpreter.cells().len(),
+        )
+        .and_then(jit::Program::into_callable)
+        .context(Compiler)?;
+
+        self.compiled.insert(start, program);
+
+        Ok(())
+    }
+
+    /// Executes a single BFR IR instruction, or a whole loop if it was compiled
+    pub fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), VmError> {
+        let pc = self.interpreter.program_counter();
+
+        match self.interpreter.program()[pc] {
+            Instruction::JumpForwardsIfZero(_) => {
+                if let Some(program) = self.compiled.get_mut(&pc) {
+                    let data_pointer = self.interpreter.data_pointer();
+                    let (data_pointer, stopped_at) = program.run_guarded(
+                        self.interpreter.cells_mut(),
+                        data_pointer,
+                        input,
+                        output,
+                    );
+
+                    // if the loop is done that skips its JumpBackwardsIfNotZero, otherwise it's
+                    // the instruction that would have left the tape
+                    self.interpreter.resume_at(pc + stopped_at, data_pointer);
+
+                    return Ok(());
+                }
+            }
+            Instruction::JumpBackwardsIfNotZero(jmp) => {
+                let cell = self.interpreter.cells()[self.interpreter.data_pointer()];
+
+                // we'll go back to the JumpForwardsIfZero, which runs the native code from then on
+                if cell != 0 {
+                    let start = pc - jmp;
+                    self.counters[start] += 1;
+
+                    if self.counters[start] == HOT_LOOP_THRESHOLD {
+                        self.compile(start, pc)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        self.interpreter.step(input, output).context(Interpreter)
+    }
+
+    /// Runs the program to end
+    pub fn vm_loop(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), VmError> {
+        while !self.interpreter.is_finished() {
+            self.step(input, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck;
+
+    fn run(source: &[u8]) -> Vec<u8> {
+        let program = ir::transform(&brainfuck::parse(source.iter().copied())).unwrap();
+        let mut output = Vec::new();
+
+        Vm::new(program)
+            .vm_loop(&mut std::io::empty(), &mut output)
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    #[should_panic]
+    fn hot_loop_off_the_end_of_the_tape() {
+        run(b"+[>+]");
+    }
+
+    #[test]
+    #[should_panic]
+    fn hot_loop_off_the_start_of_the_tape() {
+        run(b"+[<+]");
+    }
+
+    #[test]
+    fn hot_loops_run_natively_up_to_the_edges() {
+        // an inner loop that runs 1250 times, moving a cell into the one next to it
+        let mut at_the_end = b">".repeat(29_997);
+        at_the_end.extend_from_slice(&b"+".repeat(50));
+        at_the_end.extend_from_slice(b"[>");
+        at_the_end.extend_from_slice(&b"+".repeat(25));
+        at_the_end.extend_from_slice(b"[>+<-]<-]>>.");
+
+        let mut at_the_start = b">>".to_vec();
+        at_the_start.extend_from_slice(&b"+".repeat(50));
+        at_the_start.extend_from_slice(b"[<");
+        at_the_start.extend_from_slice(&b"+".repeat(25));
+        at_the_start.extend_from_slice(b"[<+>-]>-]<<.");
+
+        assert_eq!(run(&at_the_end), [226]);
+        assert_eq!(run(&at_the_start), [226]);
+    }
+}